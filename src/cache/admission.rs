@@ -0,0 +1,120 @@
+use serde::Deserialize;
+
+use super::file::{CacheFile, FileHash};
+
+/// Number of counters in each row of sketch
+const SKETCH_WIDTH: usize = 1 << 16;
+const SKETCH_DEPTH: usize = 4;
+/// Counters are halved after this many accesses, so that old popularity fades out
+const SAMPLE_SIZE: u32 = 10 * SKETCH_WIDTH as u32;
+
+/// Decide whether a fetched file is stored, or only sent to clients.
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AdmissionKind {
+    /// Store every fetched file
+    #[default]
+    Always,
+    /// Store files requested at least twice recently
+    SecondHit,
+    /// Store files requested more often recently than the one they would evict
+    TinyLfu,
+}
+
+pub struct Admission {
+    kind: AdmissionKind,
+    sketch: Option<Sketch>,
+}
+
+impl Admission {
+    pub fn new(kind: AdmissionKind) -> Admission {
+        let sketch = match kind {
+            AdmissionKind::Always => None,
+            AdmissionKind::SecondHit | AdmissionKind::TinyLfu => Some(Sketch::new()),
+        };
+        Admission { kind, sketch }
+    }
+
+    /// Record a request of file, either hit or miss.
+    pub fn record(&mut self, hash: &FileHash) {
+        if let Some(sketch) = &mut self.sketch {
+            sketch.increment(hash);
+        }
+    }
+
+    /// # Arguments
+    /// * `victim` - The next file to be evicted if the file is stored, `None` if nothing would be evicted.
+    pub fn admit(&self, file: &CacheFile, victim: Option<&CacheFile>) -> bool {
+        let Some(sketch) = &self.sketch else { return true };
+        match self.kind {
+            AdmissionKind::Always => true,
+            AdmissionKind::SecondHit => sketch.estimate(&file.hash) >= 2,
+            AdmissionKind::TinyLfu => victim.is_none_or(|v| sketch.estimate(&file.hash) > sketch.estimate(&v.hash)),
+        }
+    }
+}
+
+/// Count-min sketch of request frequency.
+struct Sketch {
+    counters: Vec<u8>,
+    additions: u32,
+}
+
+impl Sketch {
+    fn new() -> Sketch {
+        Sketch { counters: vec![0; SKETCH_WIDTH * SKETCH_DEPTH], additions: 0 }
+    }
+
+    /// Index of counter in each row, file hash is already uniform so its bytes are taken as is.
+    fn indexes(hash: &FileHash) -> impl Iterator<Item = usize> + '_ {
+        let bytes = hash.as_bytes();
+        (0..SKETCH_DEPTH).map(move |row| {
+            let n = u32::from_le_bytes(bytes[row * 4..row * 4 + 4].try_into().unwrap());
+            row * SKETCH_WIDTH + n as usize % SKETCH_WIDTH
+        })
+    }
+
+    fn increment(&mut self, hash: &FileHash) {
+        for i in Sketch::indexes(hash) {
+            self.counters[i] = self.counters[i].saturating_add(1);
+        }
+
+        self.additions += 1;
+        if self.additions >= SAMPLE_SIZE {
+            self.counters.iter_mut().for_each(|c| *c /= 2);
+            self.additions /= 2;
+        }
+    }
+
+    fn estimate(&self, hash: &FileHash) -> u8 {
+        Sketch::indexes(hash).map(|i| self.counters[i]).min().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn admission() {
+        let (a, b) = (CacheFile::for_test(1, 100), CacheFile::for_test(2, 100));
+
+        let mut second_hit = Admission::new(AdmissionKind::SecondHit);
+        second_hit.record(&a.hash);
+        assert!(!second_hit.admit(&a, None));
+        second_hit.record(&a.hash);
+        assert!(second_hit.admit(&a, None));
+
+        let mut tiny_lfu = Admission::new(AdmissionKind::TinyLfu);
+        tiny_lfu.record(&a.hash);
+        assert!(tiny_lfu.admit(&a, None));
+        tiny_lfu.record(&b.hash);
+        tiny_lfu.record(&b.hash);
+        assert!(!tiny_lfu.admit(&a, Some(&b)));
+        tiny_lfu.record(&a.hash);
+        tiny_lfu.record(&a.hash);
+        assert!(tiny_lfu.admit(&a, Some(&b)));
+
+        assert!(Admission::new(AdmissionKind::Always).admit(&a, Some(&b)));
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::utils::file_sha1;
+
+use super::file::{CacheFile, TEMP_SUFFIX};
+use super::manager::{dir_iter, shard_of};
+use super::scrub::quarantine;
+
+/// Problem of a file found in cache directory.
+#[derive(Clone, Copy)]
+enum Issue {
+    Unparsable,
+    /// Leftover of interrupted fetch
+    Temporary,
+    /// Placed in shard directory of another static range
+    Misplaced,
+    ZeroLength,
+    SizeMismatch,
+    HashMismatch,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Issue::Unparsable => "unparsable name",
+            Issue::Temporary => "temporary file",
+            Issue::Misplaced => "misplaced file",
+            Issue::ZeroLength => "zero-length file",
+            Issue::SizeMismatch => "size mismatch",
+            Issue::HashMismatch => "hash mismatch",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Default)]
+pub struct CheckReport {
+    pub checked: usize,
+    /// Number of files per [`Issue`]
+    issues: [usize; 6],
+    pub repaired: usize,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.iter().all(|n| *n == 0)
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} files checked", self.checked)?;
+        let issues = [
+            Issue::Unparsable,
+            Issue::Temporary,
+            Issue::Misplaced,
+            Issue::ZeroLength,
+            Issue::SizeMismatch,
+            Issue::HashMismatch,
+        ];
+        for issue in issues {
+            write!(f, ", {} {}", self.issues[issue as usize], issue)?;
+        }
+        write!(f, ", {} repaired", self.repaired)
+    }
+}
+
+/// Verify name, size and hash of every file in cache directory.
+///
+/// Bad files are removed, or moved into `quarantine_dir` if given, when `repair` is set.
+/// Temporary files are removed rather than quarantined. Nothing is changed without `repair`.
+pub async fn check(cache_dir: &Path, quarantine_dir: Option<&Path>, repair: bool) -> io::Result<CheckReport> {
+    let mut report = CheckReport::default();
+    for entry in dir_iter(cache_dir)? {
+        for sub_entry in dir_iter(entry.path())? {
+            let shard = shard_of(&entry, &sub_entry);
+            let mut read_dir = tokio::fs::read_dir(sub_entry.path()).await?;
+            while let Some(file_entry) = read_dir.next_entry().await? {
+                let meta = file_entry.metadata().await?;
+                if !meta.is_file() {
+                    continue;
+                }
+                report.checked += 1;
+
+                let path = file_entry.path();
+                let issue = match check_file(&path, shard, meta.len()).await {
+                    Ok(None) => continue,
+                    Ok(Some(issue)) => issue,
+                    Err(e) => {
+                        log::error!("unable to check file: {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                log::warn!("{}: {}", issue, path.display());
+                report.issues[issue as usize] += 1;
+
+                if !repair {
+                    continue;
+                }
+                let result = match (issue, quarantine_dir) {
+                    (Issue::Temporary, _) | (_, None) => tokio::fs::remove_file(&path).await,
+                    (_, Some(dir)) => quarantine(&path, dir).await,
+                };
+                match result {
+                    Ok(()) => report.repaired += 1,
+                    Err(e) => log::error!("unable to repair file: {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+async fn check_file(path: &Path, shard: u16, len: u64) -> io::Result<Option<Issue>> {
+    let Some(name) = path.file_name().and_then(|s| s.to_str()) else {
+        return Ok(Some(Issue::Unparsable));
+    };
+    if name.ends_with(TEMP_SUFFIX) {
+        return Ok(Some(Issue::Temporary));
+    }
+    let Some(file) = CacheFile::from_filename(name) else {
+        return Ok(Some(Issue::Unparsable));
+    };
+
+    let issue = if file.static_range() != shard {
+        Some(Issue::Misplaced)
+    } else if len == 0 && file.info.size != 0 {
+        Some(Issue::ZeroLength)
+    } else if len != file.info.size {
+        Some(Issue::SizeMismatch)
+    } else {
+        let hash = file_sha1(&mut tokio::fs::File::open(path).await?).await?;
+        (hash != format!("{:x}", file.hash)).then_some(Issue::HashMismatch)
+    };
+    Ok(issue)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::super::file::FileHash;
+    use super::*;
+
+    /// Fill cache directory with a good file and one file of each issue, return paths of bad files.
+    fn populate(dir: &Path) -> (PathBuf, Vec<PathBuf>) {
+        let _ = std::fs::remove_dir_all(dir);
+        let name = format!("{:x}-4-0-0.jpg", FileHash::from(openssl::sha::sha1(b"hath")));
+        let good = CacheFile::from_filename(&name).unwrap();
+        let other_shard = CacheFile::for_test(9, 4).path(dir).with_file_name(&name);
+
+        let files: [(PathBuf, &[u8]); 7] = [
+            (good.path(dir), b"hath"),
+            (CacheFile::for_test(1, 4).path(dir).with_file_name("garbage"), b"hath"),
+            (CacheFile::for_test(2, 4).temp_path(dir), b"ha"),
+            (other_shard, b"hath"),
+            (CacheFile::for_test(3, 4).path(dir), b""),
+            (CacheFile::for_test(4, 4).path(dir), b"hat"),
+            (CacheFile::for_test(5, 4).path(dir), b"hath"),
+        ];
+        for (path, data) in &files {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+        let mut paths = files.map(|(path, _)| path).into_iter();
+        (paths.next().unwrap(), paths.collect())
+    }
+
+    #[tokio::test]
+    async fn check_and_repair() {
+        let dir = std::env::temp_dir().join("hath-test-check");
+        let quarantine_dir = std::env::temp_dir().join("hath-test-check-quarantine");
+        let _ = std::fs::remove_dir_all(&quarantine_dir);
+
+        // report only
+        let (good, bad) = populate(&dir);
+        let report = check(&dir, Some(&quarantine_dir), false).await.unwrap();
+        assert_eq!((report.checked, report.issues, report.repaired), (7, [1; 6], 0));
+        assert!(!report.is_clean());
+        assert!(bad.iter().all(|p| p.exists()));
+
+        // removed
+        let report = check(&dir, None, true).await.unwrap();
+        assert_eq!((report.issues, report.repaired), ([1; 6], 6));
+        assert!(good.exists() && !bad.iter().any(|p| p.exists()));
+        assert!(check(&dir, None, false).await.unwrap().is_clean());
+
+        // quarantined, except temporary file which is removed
+        let (good, bad) = populate(&dir);
+        let report = check(&dir, Some(&quarantine_dir), true).await.unwrap();
+        assert_eq!((report.issues, report.repaired), ([1; 6], 6));
+        assert!(good.exists() && !bad.iter().any(|p| p.exists()));
+        let quarantined = std::fs::read_dir(&quarantine_dir).unwrap().count();
+        assert_eq!(quarantined, 5);
+        assert!(!quarantine_dir.join(bad[1].file_name().unwrap()).exists());
+    }
+}
@@ -7,6 +7,9 @@ use crate::utils::hex_to_u8;
 
 pub struct FetchParseError;
 
+/// Suffix of files which are being fetched
+pub const TEMP_SUFFIX: &str = ".tmp";
+
 /// Infomation of cached files
 #[derive(Clone)]
 pub struct CacheFile {
@@ -43,6 +46,13 @@ impl CacheFile {
         path
     }
 
+    /// Path to write into while fetching, which is in the same directory as [`CacheFile::path`].
+    pub fn temp_path(&self, cache_dir: &Path) -> PathBuf {
+        let mut path = self.path(cache_dir);
+        path.as_mut_os_string().push(TEMP_SUFFIX);
+        path
+    }
+
     // with extension
     pub fn filename(&self, for_api: bool) -> String {
         let mut name = format!(
@@ -58,11 +68,29 @@ impl CacheFile {
         name
     }
 
+    /// Strong entity tag, files are addressed by content so the hash is all we need.
+    pub fn etag(&self) -> HeaderValue {
+        HeaderValue::from_str(&format!("\"{:x}\"", self.hash)).unwrap()
+    }
+
     pub fn static_range(&self) -> u16 {
         u16::from_be_bytes(self.hash.0[..2].try_into().unwrap())
     }
 }
 
+#[cfg(test)]
+impl CacheFile {
+    /// A file of `size` bytes with hash made of `n`, which is in static range `n`.
+    pub fn for_test(n: u8, size: u64) -> CacheFile {
+        let mut hash = [n; 20];
+        hash[0] = 0;
+        CacheFile {
+            hash: FileHash::from(hash),
+            info: FileInfo { size, res: (0, 0), typ: FileType::from("jpg") },
+        }
+    }
+}
+
 impl TryFrom<&str> for CacheFile {
     type Error = FetchParseError;
 
@@ -87,7 +115,7 @@ impl TryFrom<&str> for CacheFile {
     }
 }
 
-#[derive(Hash, PartialEq, Eq, Clone, Copy)]
+#[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 pub struct FileHash([u8; 20]);
 
 impl LowerHex for FileHash {
@@ -101,6 +129,18 @@ impl LowerHex for FileHash {
     }
 }
 
+impl FileHash {
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+}
+
+impl From<[u8; 20]> for FileHash {
+    fn from(value: [u8; 20]) -> Self {
+        FileHash(value)
+    }
+}
+
 impl TryFrom<&str> for FileHash {
     type Error = FetchParseError;
 
@@ -121,6 +161,43 @@ impl TryFrom<&str> for FileHash {
     }
 }
 
+/// [`CacheFile`] packed into 40 bytes to be kept in index.
+///
+/// Extension of unknown file type is not kept, it should be given back on [`PackedFile::unpack`].
+#[derive(Clone, Copy)]
+pub struct PackedFile {
+    pub hash: FileHash,
+    size: u64,
+    res: (u32, u32),
+    typ: u8,
+}
+
+impl PackedFile {
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn unpack(&self, unknown: Option<&str>) -> CacheFile {
+        let info = FileInfo {
+            size: self.size,
+            res: self.res,
+            typ: FileType::from_tag(self.typ, unknown),
+        };
+        CacheFile { hash: self.hash, info }
+    }
+}
+
+impl From<&CacheFile> for PackedFile {
+    fn from(file: &CacheFile) -> Self {
+        PackedFile {
+            hash: file.hash,
+            size: file.info.size,
+            res: file.info.res,
+            typ: file.info.typ.tag(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct FileInfo {
     pub size: u64,
@@ -175,6 +252,35 @@ impl FileType {
             FileType::Unknown(s) => s.as_str(),
         }
     }
+
+    /// Tag of type in [`PackedFile`], all unknown types share the same one.
+    fn tag(&self) -> u8 {
+        match self {
+            FileType::Jpeg => 0,
+            FileType::Png => 1,
+            FileType::Gif => 2,
+            FileType::WebP => 3,
+            FileType::Avif => 4,
+            FileType::JpegXL => 5,
+            FileType::MP4 => 6,
+            FileType::WebM => 7,
+            FileType::Unknown(_) => u8::MAX,
+        }
+    }
+
+    fn from_tag(tag: u8, unknown: Option<&str>) -> FileType {
+        match tag {
+            0 => FileType::Jpeg,
+            1 => FileType::Png,
+            2 => FileType::Gif,
+            3 => FileType::WebP,
+            4 => FileType::Avif,
+            5 => FileType::JpegXL,
+            6 => FileType::MP4,
+            7 => FileType::WebM,
+            _ => FileType::Unknown(unknown.unwrap_or_default().to_owned()),
+        }
+    }
 }
 
 impl From<&str> for FileType {
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{self, Poll, Waker};
+
+use hyper::body::Bytes;
+use tokio::sync::Notify;
+
+use crate::{Error, Result};
+
+use super::file::FileHash;
+
+/// Registry of upstream fetches in progress, so that concurrent misses on
+/// the same file share a single fetch.
+///
+/// It's cloned as a handle of the same registry.
+#[derive(Default, Clone)]
+pub struct InFlight {
+    table: Arc<Mutex<HashMap<FileHash, Arc<Fetch>>>>,
+    /// Notified when the last fetch is removed
+    idle: Arc<Notify>,
+}
+
+impl InFlight {
+    pub fn get(&self, hash: &FileHash) -> Option<Arc<Fetch>> {
+        self.table.lock().unwrap().get(hash).cloned()
+    }
+
+    /// # Return
+    /// The fetch of `hash`, and whether it is newly inserted.
+    /// Caller is responsible for driving a newly inserted fetch and removing it after.
+    pub fn get_or_insert(&self, hash: FileHash) -> (Arc<Fetch>, bool) {
+        use std::collections::hash_map::Entry;
+
+        match self.table.lock().unwrap().entry(hash) {
+            Entry::Occupied(entry) => (entry.get().clone(), false),
+            Entry::Vacant(entry) => (entry.insert(Arc::default()).clone(), true),
+        }
+    }
+
+    pub fn remove(&self, hash: &FileHash) {
+        let mut table = self.table.lock().unwrap_or_else(PoisonError::into_inner);
+        table.remove(hash);
+        if table.is_empty() {
+            self.idle.notify_waiters();
+        }
+    }
+
+    /// Wait until no fetch is in progress.
+    pub async fn drained(&self) {
+        loop {
+            let mut notified = std::pin::pin!(self.idle.notified());
+            // registered before checking, so that removal in between is not missed
+            notified.as_mut().enable();
+            if self.table.lock().unwrap().is_empty() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Guard of a newly inserted fetch, which should be held by the task driving it.
+    pub fn guard(&self, hash: FileHash, fetch: Arc<Fetch>) -> FetchGuard<'_> {
+        FetchGuard { in_flight: self, hash, fetch }
+    }
+}
+
+/// Remove fetch from [`InFlight`] once dropped, and fail it if it's not finished, e.g. the task panicked.
+///
+/// The fetch is closed after, as everything of the driving task is done.
+pub struct FetchGuard<'a> {
+    in_flight: &'a InFlight,
+    hash: FileHash,
+    fetch: Arc<Fetch>,
+}
+
+impl Drop for FetchGuard<'_> {
+    fn drop(&mut self) {
+        self.fetch.abort();
+        self.in_flight.remove(&self.hash);
+        self.fetch.close();
+    }
+}
+
+/// Bytes received from upstream, which is kept until every subscriber is gone.
+#[derive(Default)]
+pub struct Fetch {
+    state: Mutex<FetchState>,
+}
+
+#[derive(Default)]
+struct FetchState {
+    chunks: Vec<Bytes>,
+    started: bool,
+    /// `Some(true)` if all bytes are received and verified.
+    finished: Option<bool>,
+    /// The driving task is done, e.g. received file is stored in cache.
+    closed: bool,
+    wakers: Vec<Waker>,
+}
+
+impl FetchState {
+    fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    fn wake_all(&mut self) {
+        self.wakers.drain(..).for_each(Waker::wake);
+    }
+}
+
+impl Fetch {
+    /// Mark upstream response as received, data is going to be pushed.
+    pub fn start(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.started = true;
+        state.wake_all();
+    }
+
+    pub fn push(&self, bytes: Bytes) {
+        let mut state = self.state.lock().unwrap();
+        state.chunks.push(bytes);
+        state.wake_all();
+    }
+
+    pub fn finish(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.finished = Some(success);
+        state.wake_all();
+    }
+
+    /// Fail the fetch unless it's finished already.
+    fn abort(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.finished.is_none() {
+            state.finished = Some(false);
+            state.wake_all();
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.closed = true;
+        state.wake_all();
+    }
+
+    /// Wait until the driving task is done, which is after the file is stored in cache if it's verified.
+    pub async fn closed(&self) {
+        poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                Poll::Ready(())
+            } else {
+                state.register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Wait for upstream response.
+    ///
+    /// # Return
+    /// `false` if the fetch failed before any data is available.
+    pub async fn ready(&self) -> bool {
+        poll_fn(|cx| {
+            let mut state = self.state.lock().unwrap();
+            if state.started {
+                Poll::Ready(true)
+            } else if state.finished.is_some() {
+                Poll::Ready(false)
+            } else {
+                state.register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    pub fn poll_chunk(&self, cx: &mut task::Context<'_>, index: usize) -> Poll<Option<Result<Bytes>>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(bytes) = state.chunks.get(index) {
+            return Poll::Ready(Some(Ok(bytes.clone())));
+        }
+
+        match state.finished {
+            Some(true) => Poll::Ready(None),
+            // make sure client would not take it as a complete file
+            Some(false) => Poll::Ready(Some(Err(Error::CorruptedFile))),
+            None => {
+                state.register(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn collect(fetch: &Fetch) -> Vec<Result<Bytes>> {
+        let mut vec = Vec::new();
+        while let Some(r) = poll_fn(|cx| fetch.poll_chunk(cx, vec.len())).await {
+            let is_err = r.is_err();
+            vec.push(r);
+            if is_err {
+                break;
+            }
+        }
+        vec
+    }
+
+    #[tokio::test]
+    async fn late_subscriber() {
+        let table = InFlight::default();
+        let hash = FileHash::from([0; 20]);
+        let (fetch, is_new) = table.get_or_insert(hash);
+        assert!(is_new);
+
+        fetch.start();
+        fetch.push(Bytes::from_static(b"hello"));
+
+        let (late, is_new) = table.get_or_insert(hash);
+        assert!(!is_new);
+        assert!(late.ready().await);
+
+        let task = tokio::spawn(async move { collect(&late).await });
+        fetch.push(Bytes::from_static(b"world"));
+        fetch.finish(true);
+        table.remove(&hash);
+
+        let chunks: Vec<_> = task.await.unwrap().into_iter().map(|r| r.ok().unwrap()).collect();
+        assert_eq!(chunks, [Bytes::from_static(b"hello"), Bytes::from_static(b"world")]);
+        assert!(table.get(&hash).is_none());
+    }
+
+    #[tokio::test]
+    async fn panicked() {
+        let table = Arc::new(InFlight::default());
+        let hash = FileHash::from([0; 20]);
+        let (fetch, _) = table.get_or_insert(hash);
+
+        let in_flight = table.clone();
+        let driver = fetch.clone();
+        let task = tokio::spawn(async move {
+            let _guard = in_flight.guard(hash, driver.clone());
+            driver.start();
+            driver.push(Bytes::from_static(b"hello"));
+            panic!("fetch panicked");
+        });
+        assert!(task.await.is_err());
+
+        let chunks = collect(&fetch).await;
+        assert!(matches!(chunks[..], [Ok(_), Err(Error::CorruptedFile)]));
+        assert!(table.get(&hash).is_none());
+    }
+
+    #[tokio::test]
+    async fn drained() {
+        let table = InFlight::default();
+        let hash = FileHash::from([0; 20]);
+        table.drained().await;
+
+        let (fetch, _) = table.get_or_insert(hash);
+        let in_flight = table.clone();
+        let waiter = fetch.clone();
+        let task = tokio::spawn(async move {
+            waiter.closed().await;
+            in_flight.drained().await;
+        });
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+
+        // finished data is not enough, e.g. it's still being stored
+        fetch.finish(true);
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+
+        drop(table.guard(hash, fetch));
+        task.await.unwrap();
+        assert!(table.get(&hash).is_none());
+    }
+
+    #[tokio::test]
+    async fn failed() {
+        let fetch = Fetch::default();
+        fetch.finish(false);
+        assert!(!fetch.ready().await);
+
+        let fetch = Fetch::default();
+        fetch.start();
+        fetch.push(Bytes::from_static(b"hello"));
+        fetch.finish(false);
+        let chunks = collect(&fetch).await;
+        assert!(matches!(chunks[..], [Ok(_), Err(Error::CorruptedFile)]));
+    }
+}
@@ -1,85 +1,719 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::{DirEntry, Metadata};
 use std::io;
-use std::path::Path;
-use std::time::SystemTime;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::utils::{LruItem, LruTable};
+use hyper::body::Bytes;
+use serde::Deserialize;
 
-use super::file::{CacheFile, FileHash};
+use crate::utils::hex_to_u8;
 
-impl LruItem for CacheFile {
-    type Key = FileHash;
+use super::admission::{Admission, AdmissionKind};
+use super::file::{CacheFile, FileHash, TEMP_SUFFIX};
+use super::inflight::InFlight;
+use super::memory::MemoryTier;
+use super::policy::{EvictionPolicy, PolicyKind};
+use super::snapshot::Snapshot;
 
-    fn key(&self) -> Self::Key {
-        self.hash
+/// How fetched files are placed among cache roots.
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Placement {
+    /// By static range, so that a shard directory only exists in one root
+    #[default]
+    Hash,
+    /// Into the root with the most free space
+    FreeSpace,
+}
+
+pub struct CacheManager {
+    /// Size limit of all roots, which is unknown until given by config or login
+    max_size: Option<u64>,
+    kind: PolicyKind,
+    roots: Vec<CacheRoot>,
+    placement: Placement,
+    /// Whether index is built
+    ready: bool,
+    /// Files removed while index is being built, which may still be found by the scan
+    removed: HashSet<FileHash>,
+    memory: MemoryTier,
+    admission: Admission,
+    /// Number of streams reading each file
+    readers: HashMap<FileHash, usize>,
+    /// Files dropped from index while being read, which are removed from disk once the last reader is gone.
+    /// Their sizes are still counted in their roots.
+    deferred: HashMap<FileHash, (usize, CacheFile)>,
+
+    /// Number of fetched files dropped for failing verification
+    rejected: u64,
+    stats: HitStats,
+}
+
+/// A cache directory, usually on its own disk.
+struct CacheRoot {
+    dir: PathBuf,
+    /// Size limit of this root, which is also limited by the total one
+    capacity: Option<u64>,
+    size: u64,
+    /// Size of this root and bytes it could grow by while keeping reserved free space of its filesystem,
+    /// both taken when free space is sampled. Headroom is negative if free space is below reserve.
+    disk_sample: Option<(u64, i64)>,
+    /// Free space is below hard floor, fetched files are not stored here
+    full: bool,
+    /// Consecutive failures on storing fetched files
+    write_failures: u32,
+    /// Files are still served, but fetched files are not stored here until writing is recovered
+    unwritable: bool,
+    policy: Box<dyn EvictionPolicy>,
+    /// Taken out of service after disk failure
+    failed: bool,
+}
+
+impl CacheRoot {
+    fn is_writable(&self) -> bool {
+        !self.failed && !self.full && !self.unwritable
+    }
+
+    fn insert(&mut self, file: CacheFile) {
+        self.size += file.info.size;
+        if let Some(previous) = self.policy.insert(file) {
+            self.size -= previous.info.size;
+        }
     }
 
-    fn key_ref(&self) -> &Self::Key {
-        &self.hash
+    fn remove(&mut self, hash: &FileHash) -> Option<CacheFile> {
+        let file = self.policy.remove(hash)?;
+        self.size -= file.info.size;
+        Some(file)
     }
 }
 
-pub struct CacheManager {
-    max_size: u64,
-    current_size: u64,
-    table: LruTable<CacheFile>,
+/// Hit statistics since last report
+#[derive(Default)]
+struct HitStats {
+    hits: u64,
+    /// Hits served from memory, which are included in `hits`
+    memory_hits: u64,
+    misses: u64,
+    /// Fetched files stored into cache
+    admitted: u64,
+    /// Fetched files only sent to clients by admission policy
+    declined: u64,
+    hit_bytes: u64,
+    miss_bytes: u64,
 }
 
 impl CacheManager {
-    pub fn new() -> CacheManager {
+    /// Create a manager of cache `roots`, each of which is a directory with optional capacity.
+    pub fn new(policy: PolicyKind, roots: Vec<(PathBuf, Option<u64>)>, placement: Placement) -> CacheManager {
+        assert!(!roots.is_empty(), "no cache directory");
+        let roots = roots
+            .into_iter()
+            .map(|(dir, capacity)| CacheRoot {
+                dir,
+                capacity,
+                size: 0,
+                disk_sample: None,
+                full: false,
+                write_failures: 0,
+                unwritable: false,
+                policy: policy.build(),
+                failed: false,
+            })
+            .collect();
         CacheManager {
-            max_size: 0,
-            current_size: 0,
-            table: LruTable::new(),
+            max_size: None,
+            kind: policy,
+            roots,
+            placement,
+            ready: false,
+            removed: HashSet::new(),
+            memory: MemoryTier::new(0),
+            admission: Admission::new(AdmissionKind::Always),
+            readers: HashMap::new(),
+            deferred: HashMap::new(),
+            rejected: 0,
+            stats: HitStats::default(),
         }
     }
 
     pub fn set_max_size(&mut self, max_size: u64) {
-        self.max_size = max_size;
+        self.max_size = Some(max_size);
+        for i in 0..self.roots.len() {
+            self.evict(i);
+        }
     }
 
-    // Sychrononse
-    pub fn build<P: AsRef<Path>>(&mut self, cache_dir: P) -> io::Result<()> {
-        let mut vec: Vec<(SystemTime, CacheFile)> = Vec::new();
-        for entry in dir_iter(cache_dir)? {
-            for entry in dir_iter(entry.path())? {
-                for (file, meta) in file_iter(entry.path())? {
-                    let time = meta.accessed().unwrap();
-                    vec.push((time, file));
+    /// Set memory budget of hot files, memory tier is disabled if it's 0.
+    pub fn set_memory_size(&mut self, size: u64) {
+        self.memory = MemoryTier::new(size);
+    }
+
+    pub fn set_memory_enabled(&mut self, enabled: bool) {
+        self.memory.set_enabled(enabled);
+    }
+
+    pub fn set_admission(&mut self, kind: AdmissionKind) {
+        self.admission = Admission::new(kind);
+    }
+
+    /// Directories of roots in service.
+    pub fn roots(&self) -> Vec<(usize, PathBuf)> {
+        self.live_roots().map(|(i, r)| (i, r.dir.clone())).collect()
+    }
+
+    pub fn root_count(&self) -> usize {
+        self.roots.len()
+    }
+
+    fn live_roots(&self) -> impl Iterator<Item = (usize, &CacheRoot)> {
+        self.roots.iter().enumerate().filter(|(_, r)| !r.failed)
+    }
+
+    fn current_size(&self) -> u64 {
+        self.roots.iter().map(|r| r.size).sum()
+    }
+
+    fn capacity(&self, root: &CacheRoot) -> u64 {
+        let max_size = self.max_size.unwrap_or(u64::MAX);
+        let capacity = root.capacity.map_or(max_size, |c| c.min(max_size));
+        let disk_limit = root.disk_sample.map(|(size, headroom)| size.saturating_add_signed(headroom));
+        disk_limit.map_or(capacity, |l| l.min(capacity))
+    }
+
+    /// Limit root by free space of its filesystem, which is checked periodically.
+    ///
+    /// Files are evicted to keep `reserve` bytes free, and no file is stored in it while free space is below `floor`.
+    pub fn set_free_space(&mut self, root: usize, free: u64, reserve: u64, floor: u64) {
+        let r = &mut self.roots[root];
+        // size is not known until index is built
+        if self.ready {
+            let headroom = free.min(i64::MAX as u64) as i64 - reserve.min(i64::MAX as u64) as i64;
+            r.disk_sample = Some((r.size, headroom));
+        }
+
+        let full = free < floor;
+        if full && !r.full {
+            log::warn!("cache directory {} is nearly full, {} bytes free, stop storing files", r.dir.display(), free);
+        } else if !full && r.full {
+            log::info!("cache directory {} has {} bytes free, start storing files", r.dir.display(), free);
+        }
+        r.full = full;
+        self.evict(root);
+    }
+
+    /// Load files found by [`scan`] into index of each root, and start eviction once size limit is known.
+    ///
+    /// Files added before are kept as the most recent ones.
+    pub fn load(&mut self, scanned: Vec<io::Result<Vec<CacheFile>>>) {
+        let removed = std::mem::take(&mut self.removed);
+        for (i, result) in scanned.into_iter().enumerate() {
+            // taken out of service while index is being built
+            if self.roots[i].failed {
+                continue;
+            }
+            let root = &mut self.roots[i];
+            let added = std::mem::replace(&mut root.policy, self.kind.build());
+            root.size = 0;
+            let result = result.map(|files| {
+                for file in files.into_iter().filter(|f| !removed.contains(&f.hash)) {
+                    root.insert(file);
                 }
+            });
+            for file in added.iter() {
+                root.insert(file);
+            }
+            if let Err(e) = result {
+                self.fail_root(i, &e);
             }
         }
 
-        vec.sort_unstable_by_key(|x| x.0);
-        for (_, file) in vec {
-            self.current_size += file.info.size;
-            self.table.push_front(file);
+        self.ready = true;
+        for i in 0..self.roots.len() {
+            self.evict(i);
         }
-        Ok(())
     }
 
-    pub fn add(&mut self, cache_dir: &Path, file: CacheFile) {
-        self.current_size += file.info.size;
-        self.table.push_front(file);
+    /// Encode index of root into snapshot, with given `time` which should be taken before any pending changes.
+    ///
+    /// Return `None` if index is still being built, or the root is out of service.
+    pub fn snapshot(&self, root: usize, time: SystemTime) -> Option<Vec<u8>> {
+        let root = &self.roots[root];
+        (self.ready && !root.failed).then(|| Snapshot::encode(&root.dir, time, root.policy.iter()))
+    }
 
-        while self.current_size > self.max_size {
-            let Some(file) = self.table.pop_back() else { break };
+    /// Choose a root to store a fetched file.
+    ///
+    /// Return `None` if no root is writable, fetched files are only sent to clients then.
+    pub fn place(&self, file: &CacheFile) -> Option<(usize, PathBuf)> {
+        let i = match self.placement {
+            Placement::Hash => {
+                // fall back to the next root, so that files of other roots stay where they are
+                let start = file.static_range() as usize % self.roots.len();
+                (start..self.roots.len()).chain(0..start).find(|i| self.roots[*i].is_writable())?
+            }
+            Placement::FreeSpace => {
+                let free = |r: &CacheRoot| self.capacity(r).saturating_sub(r.size);
+                self.live_roots().filter(|(_, r)| r.is_writable()).max_by_key(|(_, r)| free(r))?.0
+            }
+        };
+        Some((i, self.roots[i].dir.clone()))
+    }
+
+    /// Whether the file is indexed in any root in service.
+    pub fn contains(&self, file: &CacheFile) -> bool {
+        self.live_roots().any(|(_, r)| r.policy.contains(&file.hash))
+    }
+
+    /// Paths where the file could be found, along with their roots.
+    pub fn locate(&self, file: &CacheFile) -> Vec<(usize, PathBuf)> {
+        if let Some((i, root)) = self.live_roots().find(|(_, r)| r.policy.contains(&file.hash)) {
+            return vec![(i, file.path(&root.dir))];
+        }
+        match self.ready {
+            true => self.place(file).map(|(i, dir)| (i, file.path(&dir))).into_iter().collect(),
+            // it might be in any root before index is built
+            false => self.live_roots().map(|(i, r)| (i, file.path(&r.dir))).collect(),
+        }
+    }
+
+    pub fn add(&mut self, root: usize, file: CacheFile) {
+        if self.roots[root].failed {
+            return;
+        }
+        self.roots[root].write_failures = 0;
+        self.removed.remove(&file.hash);
+        // previous file is replaced on disk
+        self.forget_deferred(&file.hash);
+        self.roots[root].insert(file);
+        self.evict(root);
+    }
 
-            let path = file.path(cache_dir);
-            tokio::spawn(async move {
-                if let Err(e) = tokio::fs::remove_file(path).await {
-                    log::error!("unable to remove file: {}: {}", file.filename(false), e);
+    fn evict(&mut self, root: usize) {
+        // files not in index yet would be evicted unexpectedly, and so would all files without a known size limit
+        let Some(max_size) = self.max_size.filter(|_| self.ready) else { return };
+        // files of failed root are not indexed, and its disk should not be touched
+        if self.roots[root].failed {
+            return;
+        }
+
+        while self.roots[root].size > self.capacity(&self.roots[root]) {
+            if !self.evict_from(root) {
+                break;
+            }
+        }
+        while self.current_size() > max_size {
+            // other roots are only evicted when this one is empty, e.g. total size is reduced
+            let mut candidates = std::iter::once(root).chain(0..self.roots.len());
+            let Some(i) = candidates.find(|i| !self.roots[*i].failed && self.roots[*i].size != 0) else { break };
+            if !self.evict_from(i) {
+                break;
+            }
+        }
+    }
+
+    fn evict_from(&mut self, root: usize) -> bool {
+        let Some(file) = self.roots[root].policy.evict() else { return false };
+        self.discard(root, file);
+        true
+    }
+
+    /// Remove a file dropped from index of root from disk, or defer it until the file is not read.
+    fn discard(&mut self, root: usize, file: CacheFile) {
+        self.memory.remove(&file.hash);
+        if self.readers.contains_key(&file.hash) {
+            self.deferred.insert(file.hash, (root, file));
+            return;
+        }
+        let root = &mut self.roots[root];
+        root.size -= file.info.size;
+        remove_file(&root.dir, file);
+    }
+
+    /// Drop deferred removal of file, which is going to be removed or replaced by caller.
+    fn forget_deferred(&mut self, hash: &FileHash) {
+        if let Some((root, file)) = self.deferred.remove(hash) {
+            self.roots[root].size -= file.info.size;
+        }
+    }
+
+    /// Give up waiting for readers of files pending removal, returning their paths to be removed by caller.
+    pub fn take_deferred(&mut self) -> Vec<PathBuf> {
+        let deferred = std::mem::take(&mut self.deferred);
+        deferred
+            .into_values()
+            .map(|(root, file)| {
+                let root = &mut self.roots[root];
+                root.size -= file.info.size;
+                file.path(&root.dir)
+            })
+            .collect()
+    }
+
+    /// Mark file as being read, so that it's kept on disk until [`CacheManager::release`].
+    pub fn acquire(&mut self, hash: &FileHash) {
+        *self.readers.entry(*hash).or_default() += 1;
+    }
+
+    pub fn release(&mut self, hash: &FileHash) {
+        let Some(count) = self.readers.get_mut(hash) else { return };
+        *count -= 1;
+        if *count != 0 {
+            return;
+        }
+        self.readers.remove(hash);
+        if let Some((root, file)) = self.deferred.remove(hash) {
+            self.discard(root, file);
+        }
+    }
+
+    /// Take root out of service if the error indicates a disk failure, other errors are usually transient.
+    ///
+    /// Its files are dropped from index, but kept on disk until it's restored by [`CacheManager::restore_root`].
+    pub fn io_error(&mut self, root: usize, e: &io::Error) {
+        if is_disk_failure(e) {
+            self.fail_root(root, e);
+        }
+    }
+
+    /// Roots taken out of service after disk failure.
+    pub fn failed_roots(&self) -> Vec<(usize, PathBuf)> {
+        self.roots.iter().enumerate().filter(|(_, r)| r.failed).map(|(i, r)| (i, r.dir.clone())).collect()
+    }
+
+    /// Put a root back into service with files found by [`scan`].
+    ///
+    /// Files stored into other roots meanwhile are kept there.
+    pub fn restore_root(&mut self, root: usize, files: Vec<CacheFile>) {
+        if !self.roots[root].failed {
+            return;
+        }
+        for file in files {
+            if !self.roots.iter().any(|r| r.policy.contains(&file.hash)) {
+                self.roots[root].insert(file);
+            }
+        }
+        let r = &mut self.roots[root];
+        log::info!("cache directory {} is back in service, {} bytes indexed", r.dir.display(), r.size);
+        r.failed = false;
+        r.unwritable = false;
+        r.write_failures = 0;
+        self.evict(root);
+    }
+
+    /// Record a failure on storing fetched file into root.
+    ///
+    /// Root is not used for storing after several consecutive failures, e.g. filesystem goes read-only.
+    pub fn write_error(&mut self, root: usize, e: &io::Error) {
+        const MAX_FAILURES: u32 = 3;
+
+        let r = &mut self.roots[root];
+        r.write_failures += 1;
+        if r.write_failures >= MAX_FAILURES && !r.unwritable {
+            log::warn!("cache directory {} is not writable: {}, stop storing files", r.dir.display(), e);
+            r.unwritable = true;
+            if !self.roots.iter().any(CacheRoot::is_writable) {
+                log::warn!("no cache directory is writable, fetched files are only sent to clients");
+            }
+        }
+    }
+
+    /// Roots not used for storing after write failures.
+    pub fn unwritable_roots(&self) -> Vec<(usize, PathBuf)> {
+        self.live_roots().filter(|(_, r)| r.unwritable).map(|(i, r)| (i, r.dir.clone())).collect()
+    }
+
+    pub fn set_writable(&mut self, root: usize) {
+        let r = &mut self.roots[root];
+        if r.unwritable {
+            log::info!("cache directory {} is writable again, start storing files", r.dir.display());
+        }
+        r.unwritable = false;
+        r.write_failures = 0;
+    }
+
+    fn fail_root(&mut self, index: usize, e: &io::Error) {
+        let root = &mut self.roots[index];
+        if root.failed {
+            return;
+        }
+        log::error!("cache directory {} is taken out of service: {}", root.dir.display(), e);
+        root.failed = true;
+        root.size = 0;
+        root.disk_sample = None;
+        let policy = std::mem::replace(&mut root.policy, self.kind.build());
+        for file in policy.iter() {
+            self.memory.remove(&file.hash);
+        }
+        self.deferred.retain(|_, (r, _)| *r != index);
+    }
+
+    /// Remove files out of assigned static ranges from index and disk.
+    ///
+    /// # Return
+    /// Number of files and bytes removed.
+    pub fn remove_out_of_range(&mut self, ranges: &HashSet<u16>) -> (usize, u64) {
+        let (mut count, mut size) = (0, 0);
+        for root in 0..self.roots.len() {
+            let policy = &self.roots[root].policy;
+            let hashes: Vec<_> = policy.iter().filter(|f| !ranges.contains(&f.static_range())).map(|f| f.hash).collect();
+            for hash in &hashes {
+                if let Some(file) = self.roots[root].policy.remove(hash) {
+                    count += 1;
+                    size += file.info.size;
+                    self.discard(root, file);
                 }
-            });
+            }
+        }
+        (count, size)
+    }
+
+    /// Hashes of indexed files along with their roots, in no particular order.
+    pub fn indexed_hashes(&self) -> Vec<(usize, FileHash)> {
+        self.live_roots().flat_map(|(i, r)| r.policy.iter().map(move |f| (i, f.hash))).collect()
+    }
+
+    /// Look up a file indexed in `root`, along with directory of the root.
+    pub fn lookup(&self, root: usize, hash: &FileHash) -> Option<(PathBuf, CacheFile)> {
+        let r = self.roots.get(root).filter(|r| !r.failed)?;
+        r.policy.get(hash).map(|file| (r.dir.clone(), file))
+    }
+
+    /// Record an access of file in its root.
+    fn access(&mut self, hash: &FileHash) -> bool {
+        self.roots.iter_mut().any(|r| r.policy.access(hash))
+    }
+
+    /// Record a cache hit of file on disk.
+    ///
+    /// # Return
+    /// `true` if the file should be loaded into memory by [`CacheManager::load_memory`].
+    pub fn update(&mut self, file: &CacheFile) -> bool {
+        self.stats.hits += 1;
+        self.stats.hit_bytes += file.info.size;
+        self.admission.record(&file.hash);
+        self.access(&file.hash) && self.memory.hit(file)
+    }
+
+    /// Serve a cache hit from memory if file is there.
+    ///
+    /// # Return
+    /// Content and modification time of file.
+    pub fn get_memory(&mut self, file: &CacheFile) -> Option<(Bytes, Option<SystemTime>)> {
+        let ret = self.memory.get(&file.hash)?;
+        self.access(&file.hash);
+        self.admission.record(&file.hash);
+        self.stats.hits += 1;
+        self.stats.hit_bytes += file.info.size;
+        self.stats.memory_hits += 1;
+        Some(ret)
+    }
+
+    /// Keep content of file in memory, unless it's removed from index meanwhile.
+    pub fn load_memory(&mut self, file: &CacheFile, data: Bytes, modified: Option<SystemTime>) {
+        if self.contains(file) && data.len() as u64 == file.info.size {
+            self.memory.insert(file.hash, data, modified);
+        }
+    }
+
+    /// Record a cache miss of file, it's added after fetched.
+    pub fn miss(&mut self, file: &CacheFile) {
+        self.stats.misses += 1;
+        self.stats.miss_bytes += file.info.size;
+        self.admission.record(&file.hash);
+    }
+
+    /// Decide whether a file about to be fetched is stored into `root`, by admission policy.
+    pub fn admit(&mut self, root: usize, file: &CacheFile) -> bool {
+        let r = &self.roots[root];
+        let victim = (r.size + file.info.size > self.capacity(r)).then(|| r.policy.iter().next()).flatten();
+        let admitted = self.admission.admit(file, victim.as_ref());
+        match admitted {
+            true => self.stats.admitted += 1,
+            false => self.stats.declined += 1,
         }
+        admitted
     }
 
-    pub fn update(&mut self, file: &CacheFile) {
-        self.table.get(file.key_ref());
+    /// Log hit ratio since last report.
+    pub fn report(&mut self) {
+        let stats = std::mem::take(&mut self.stats);
+        let ratio = |a: u64, b: u64| if a + b == 0 { 0.0 } else { a as f64 * 100.0 / (a + b) as f64 };
+        log::info!(
+            "cache policy {}: hit ratio {:.2}% ({} hits, {} from memory, {} misses), byte hit ratio {:.2}%, \
+            {} of {} bytes used, {} bytes in memory, {} fetched files admitted, {} declined",
+            self.roots[0].policy.name(),
+            ratio(stats.hits, stats.misses),
+            stats.hits,
+            stats.memory_hits,
+            stats.misses,
+            ratio(stats.hit_bytes, stats.miss_bytes),
+            self.current_size(),
+            self.max_size.unwrap_or_default(),
+            self.memory.used(),
+            stats.admitted,
+            stats.declined,
+        );
+        if self.roots.len() > 1 {
+            for root in &self.roots {
+                let state = match root {
+                    r if r.failed => ", out of service",
+                    r if r.unwritable => ", not writable",
+                    r if r.full => ", nearly full",
+                    _ => "",
+                };
+                let capacity = self.capacity(root);
+                log::info!("cache directory {}: {} of {} bytes used{}", root.dir.display(), root.size, capacity, state);
+            }
+        }
+    }
+
+    /// Remove a file from the index, the caller is responsible for removing it from disk.
+    pub fn remove(&mut self, file: &CacheFile) {
+        if !self.ready {
+            self.removed.insert(file.hash);
+        }
+        self.memory.remove(&file.hash);
+        self.forget_deferred(&file.hash);
+        for root in &mut self.roots {
+            root.remove(&file.hash);
+        }
+    }
+
+    /// Add a file found in `root` by others, unless it's indexed already.
+    pub fn found(&mut self, root: usize, file: CacheFile) {
+        if self.roots[root].failed || self.roots.iter().any(|r| r.policy.contains(&file.hash)) {
+            return;
+        }
+        self.removed.remove(&file.hash);
+        self.roots[root].insert(file);
+        self.evict(root);
     }
+
+    /// Remove a file from index of `root` as it's removed from disk by others.
+    pub fn lost(&mut self, root: usize, file: &CacheFile) {
+        if !self.ready {
+            self.removed.insert(file.hash);
+        }
+        if self.roots[root].remove(&file.hash).is_some() {
+            self.memory.remove(&file.hash);
+        }
+    }
+
+    /// Same as [`CacheManager::remove`], but for fetched files failed on verification.
+    pub fn reject(&mut self, file: &CacheFile) {
+        self.remove(file);
+        self.rejected += 1;
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+/// Build index from snapshot, only shard directories modified after the snapshot are walked through.
+/// Walk through the whole cache directory if snapshot is missing or broken.
+///
+/// Temporary files are removed from walked directories, except those of `in_flight` fetches.
+///
+/// # Return
+/// Files in order of eviction.
+pub fn scan(cache_dir: &Path, snapshot: &Path, in_flight: &InFlight) -> io::Result<Vec<CacheFile>> {
+    let snapshot = match std::fs::read(snapshot) {
+        Ok(data) => Snapshot::decode(&data),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    std::fs::create_dir_all(cache_dir)?;
+    // e.g. cache directories are reordered in config
+    let snapshot = snapshot.filter(|s| {
+        let matched = s.is_of(cache_dir);
+        if !matched {
+            log::warn!("index snapshot is taken from {}, not {}", s.dir.display(), cache_dir.display());
+        }
+        matched
+    });
+    if snapshot.is_none() {
+        log::info!("index snapshot is not available, scanning whole cache directory");
+    }
+    let entries: Vec<_> = dir_iter(cache_dir)?.collect();
+
+    // shards which are not modified since snapshot
+    let mut fresh = HashSet::new();
+    let mut scanned = 0;
+    let mut vec: Vec<(SystemTime, CacheFile)> = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        for sub_entry in dir_iter(entry.path())? {
+            let shard = shard_of(entry, &sub_entry);
+            let modified = sub_entry.metadata().and_then(|m| m.modified()).ok();
+            if let (Some(snapshot), Some(modified)) = (&snapshot, modified)
+                && is_fresh(modified, snapshot.time)
+            {
+                fresh.insert(shard);
+                continue;
+            }
+
+            scanned += 1;
+            for (file, meta) in file_iter(sub_entry.path(), in_flight)? {
+                // not every filesystem records access time
+                let time = meta.accessed().or_else(|_| meta.modified()).unwrap_or(UNIX_EPOCH);
+                vec.push((time, file));
+            }
+        }
+
+        if (i + 1) % 16 == 0 {
+            log::info!("building cache index: {}/{} directories, {} files found", i + 1, entries.len(), vec.len());
+        }
+    }
+
+    let mut files: Vec<_> =
+        snapshot.into_iter().flat_map(|s| s.files).filter(|f| fresh.contains(&f.static_range())).collect();
+    let loaded = files.len();
+
+    // files in modified shards are most likely recently fetched
+    vec.sort_unstable_by_key(|x| x.0);
+    files.extend(vec.into_iter().map(|(_, file)| file));
+    log::info!(
+        "cache index built: {} files loaded from snapshot, {} files found in {} modified directories",
+        loaded,
+        files.len() - loaded,
+        scanned
+    );
+    Ok(files)
+}
+
+/// Whether the error is from the disk itself, rather than e.g. running out of file descriptors.
+#[cfg(unix)]
+fn is_disk_failure(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EIO | libc::ENXIO | libc::ENODEV))
 }
 
-fn dir_iter<P: AsRef<Path>>(path: P) -> io::Result<impl Iterator<Item = DirEntry>> {
+#[cfg(not(unix))]
+fn is_disk_failure(_e: &io::Error) -> bool {
+    false
+}
+
+/// Whether a shard directory modified at `modified` is not changed since snapshot taken at `time`.
+///
+/// Changes within the same second may not be told apart on filesystems with coarse mtime.
+fn is_fresh(modified: SystemTime, time: SystemTime) -> bool {
+    const MTIME_GRANULARITY: Duration = Duration::from_secs(1);
+
+    time.checked_sub(MTIME_GRANULARITY).is_some_and(|t| modified < t)
+}
+
+fn remove_file(cache_dir: &Path, file: CacheFile) {
+    let path = file.path(cache_dir);
+    tokio::spawn(async move {
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            log::error!("unable to remove file: {}: {}", file.filename(false), e);
+        }
+    });
+}
+
+pub(super) fn dir_iter<P: AsRef<Path>>(path: P) -> io::Result<impl Iterator<Item = DirEntry>> {
     fn is_u8_hex(bytes: &[u8]) -> bool {
         bytes.len() == 2 && bytes.iter().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(c))
     }
@@ -95,14 +729,37 @@ fn dir_iter<P: AsRef<Path>>(path: P) -> io::Result<impl Iterator<Item = DirEntry
     }))
 }
 
-fn file_iter<P: AsRef<Path>>(path: P) -> io::Result<impl Iterator<Item = (CacheFile, Metadata)>> {
+/// Static range of shard directory `xx/yy`.
+pub(super) fn shard_of(entry: &DirEntry, sub_entry: &DirEntry) -> u16 {
+    let hex = |e: &DirEntry| {
+        let name = e.file_name();
+        let b = name.as_encoded_bytes();
+        hex_to_u8(b[0], b[1]).unwrap()
+    };
+    u16::from_be_bytes([hex(entry), hex(sub_entry)])
+}
+
+/// Files in directory, temporary files are removed unless they're being fetched.
+fn file_iter<P: AsRef<Path>>(
+    path: P,
+    in_flight: &InFlight,
+) -> io::Result<impl Iterator<Item = (CacheFile, Metadata)>> {
     let read_dir = std::fs::read_dir(path)?;
-    Ok(read_dir.into_iter().filter_map(|entry| {
+    Ok(read_dir.into_iter().filter_map(move |entry| {
         let entry = entry.ok()?;
         let meta = entry.metadata().ok()?;
         if meta.is_file() {
             let path = entry.path();
             let name = path.file_name().and_then(|s| s.to_str())?;
+            if let Some(stem) = name.strip_suffix(TEMP_SUFFIX) {
+                // index is built while serving, so only leftovers of interrupted fetches are removed
+                let fetching = CacheFile::from_filename(stem).is_some_and(|f| in_flight.get(&f.hash).is_some());
+                if !fetching {
+                    log::debug!("remove temporary file: {}", name);
+                    let _ = std::fs::remove_file(&path);
+                }
+                return None;
+            }
             let file = CacheFile::from_filename(name)?;
             return Some((file, meta));
         }
@@ -110,5 +767,203 @@ fn file_iter<P: AsRef<Path>>(path: P) -> io::Result<impl Iterator<Item = (CacheF
     }))
 }
 
-unsafe impl Send for CacheManager {}
-unsafe impl Sync for CacheManager {}
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn roots() {
+        let dir = std::env::temp_dir().join("hath-test-roots");
+        let roots = vec![(dir.join("0"), Some(300)), (dir.join("1"), None)];
+        let mut manager = CacheManager::new(PolicyKind::Lru, roots, Placement::Hash);
+        manager.set_max_size(1000);
+        manager.load(vec![Ok(Vec::new()), Ok(Vec::new())]);
+
+        // placed by static range
+        assert_eq!(manager.place(&CacheFile::for_test(2, 100)).unwrap().0, 0);
+        assert_eq!(manager.place(&CacheFile::for_test(3, 100)).unwrap().0, 1);
+
+        // evicted by capacity of root
+        for n in [2, 4, 6, 8] {
+            manager.add(0, CacheFile::for_test(n, 100));
+        }
+        assert_eq!(manager.roots[0].size, 300);
+        let located = CacheFile::for_test(4, 100).path(&dir.join("0"));
+        assert_eq!(manager.locate(&CacheFile::for_test(4, 100)), [(0, located)]);
+
+        // evicted by total size
+        for n in [3, 5, 7, 9, 11, 13, 15, 17] {
+            manager.add(1, CacheFile::for_test(n, 100));
+        }
+        assert_eq!(manager.current_size(), 1000);
+        assert_eq!(manager.roots[1].size, 700);
+
+        manager.io_error(0, &io::Error::from(io::ErrorKind::NotFound));
+        manager.io_error(0, &io::Error::from(io::ErrorKind::TimedOut));
+        assert!(!manager.roots[0].failed);
+        manager.io_error(0, &io::Error::other("disk failure"));
+        assert!(!manager.roots[0].failed);
+        manager.io_error(0, &io::Error::from_raw_os_error(libc::EIO));
+        assert_eq!(manager.roots(), [(1, dir.join("1"))]);
+        assert_eq!(manager.current_size(), 700);
+        assert_eq!(manager.place(&CacheFile::for_test(2, 100)).unwrap().0, 1);
+
+        // shrunk to keep reserved space
+        manager.set_free_space(1, 50, 100, 10);
+        assert_eq!(manager.roots[1].size, 600);
+        manager.set_free_space(1, 5, 100, 10);
+        assert!(manager.place(&CacheFile::for_test(2, 100)).is_none());
+        manager.set_free_space(1, 50, 100, 10);
+
+        let e = io::Error::from(io::ErrorKind::ReadOnlyFilesystem);
+        manager.write_error(1, &e);
+        manager.write_error(1, &e);
+        assert!(manager.place(&CacheFile::for_test(2, 100)).is_some());
+        manager.write_error(1, &e);
+        assert!(manager.place(&CacheFile::for_test(2, 100)).is_none());
+        // files are still served
+        assert_eq!(manager.locate(&CacheFile::for_test(17, 100)).len(), 1);
+        assert_eq!(manager.unwritable_roots(), [(1, dir.join("1"))]);
+        manager.set_writable(1);
+        assert!(manager.place(&CacheFile::for_test(2, 100)).is_some());
+
+        // files stored into the other root meanwhile are kept there
+        assert_eq!(manager.failed_roots(), [(0, dir.join("0"))]);
+        manager.restore_root(0, vec![CacheFile::for_test(2, 100), CacheFile::for_test(17, 100)]);
+        assert!(manager.failed_roots().is_empty());
+        assert_eq!(manager.roots[0].size, 100);
+        let located = CacheFile::for_test(17, 100).path(&dir.join("1"));
+        assert_eq!(manager.locate(&CacheFile::for_test(17, 100)), [(1, located)]);
+    }
+
+    #[test]
+    fn scan_temp_files() {
+        let dir = std::env::temp_dir().join("hath-test-scan");
+        let _ = std::fs::remove_dir_all(&dir);
+        let (cached, fetching, leftover) =
+            (CacheFile::for_test(1, 4), CacheFile::for_test(2, 4), CacheFile::for_test(3, 4));
+        for path in [cached.path(&dir), fetching.temp_path(&dir), leftover.temp_path(&dir)] {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"hath").unwrap();
+        }
+
+        let in_flight = InFlight::default();
+        in_flight.get_or_insert(fetching.hash);
+        let files = scan(&dir, &dir.join("no_snapshot"), &in_flight).unwrap();
+        assert!(files.len() == 1 && files[0].hash == cached.hash);
+        assert!(fetching.temp_path(&dir).exists());
+        assert!(!leftover.temp_path(&dir).exists());
+    }
+
+    #[test]
+    fn snapshot_freshness() {
+        let time = UNIX_EPOCH + Duration::from_secs(1000);
+        assert!(is_fresh(time - Duration::from_secs(2), time));
+        // mtime may be truncated to seconds
+        assert!(!is_fresh(time - Duration::from_millis(500), time));
+        assert!(!is_fresh(time, time));
+    }
+
+    #[tokio::test]
+    async fn unknown_capacity() {
+        let dir = std::env::temp_dir().join("hath-test-unknown-capacity");
+        let mut manager = CacheManager::new(PolicyKind::Lru, vec![(dir, None)], Placement::Hash);
+        // sampled before index is built, when size of root is not known
+        manager.set_free_space(0, 150, 100, 10);
+        manager.load(vec![Ok(vec![CacheFile::for_test(1, 100), CacheFile::for_test(2, 100)])]);
+        manager.add(0, CacheFile::for_test(3, 100));
+        assert_eq!(manager.current_size(), 300);
+
+        // evicted once size limit is given by login
+        manager.set_max_size(200);
+        assert_eq!(manager.current_size(), 200);
+        assert!(!manager.roots[0].policy.contains(&CacheFile::for_test(1, 100).hash));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failed_while_loading() {
+        let dir = std::env::temp_dir().join("hath-test-failed-loading");
+        let roots = vec![(dir.join("0"), None), (dir.join("1"), None)];
+        let mut manager = CacheManager::new(PolicyKind::Lru, roots, Placement::Hash);
+        manager.set_max_size(200);
+        manager.io_error(0, &io::Error::from_raw_os_error(libc::EIO));
+
+        let scanned = |n: u8| Ok(vec![CacheFile::for_test(n, 100), CacheFile::for_test(n + 2, 100)]);
+        manager.load(vec![scanned(2), scanned(3)]);
+        assert_eq!(manager.roots[0].size, 0);
+        assert!(!manager.roots[0].policy.contains(&CacheFile::for_test(2, 100).hash));
+        assert_eq!(manager.current_size(), 200);
+
+        // files of failed root are found again in other roots
+        manager.found(1, CacheFile::for_test(2, 100));
+        assert!(manager.roots[1].policy.contains(&CacheFile::for_test(2, 100).hash));
+        assert_eq!(manager.current_size(), 200);
+    }
+
+    #[tokio::test]
+    async fn deferred_removal() {
+        let dir = std::env::temp_dir().join("hath-test-deferred");
+        let mut manager = CacheManager::new(PolicyKind::Lru, vec![(dir, None)], Placement::Hash);
+        manager.set_max_size(200);
+        manager.load(vec![Ok(Vec::new())]);
+
+        manager.add(0, CacheFile::for_test(1, 100));
+        manager.add(0, CacheFile::for_test(2, 100));
+        manager.acquire(&CacheFile::for_test(1, 100).hash);
+        manager.acquire(&CacheFile::for_test(1, 100).hash);
+
+        // evicted file is still counted until the last reader is gone, so that another one is evicted
+        manager.add(0, CacheFile::for_test(3, 100));
+        assert!(!manager.roots[0].policy.contains(&CacheFile::for_test(1, 100).hash));
+        assert!(!manager.roots[0].policy.contains(&CacheFile::for_test(2, 100).hash));
+        assert_eq!(manager.current_size(), 200);
+
+        manager.release(&CacheFile::for_test(1, 100).hash);
+        assert_eq!(manager.current_size(), 200);
+        manager.release(&CacheFile::for_test(1, 100).hash);
+        assert_eq!(manager.current_size(), 100);
+        manager.add(0, CacheFile::for_test(4, 100));
+        assert_eq!(manager.current_size(), 200);
+
+        // removed regardless of readers at shutdown
+        manager.acquire(&CacheFile::for_test(3, 100).hash);
+        manager.add(0, CacheFile::for_test(5, 100));
+        assert_eq!(manager.current_size(), 200);
+        let located = CacheFile::for_test(3, 100).path(&manager.roots[0].dir);
+        assert_eq!(manager.take_deferred(), [located]);
+        assert_eq!(manager.current_size(), 100);
+        assert!(manager.take_deferred().is_empty());
+    }
+
+    #[tokio::test]
+    async fn external_changes() {
+        let dir = std::env::temp_dir().join("hath-test-external");
+        let roots = vec![(dir.join("0"), None), (dir.join("1"), None)];
+        let mut manager = CacheManager::new(PolicyKind::Lru, roots, Placement::Hash);
+        manager.set_max_size(200);
+        manager.load(vec![Ok(Vec::new()), Ok(Vec::new())]);
+
+        manager.add(0, CacheFile::for_test(2, 100));
+        manager.found(1, CacheFile::for_test(2, 100));
+        manager.found(1, CacheFile::for_test(3, 100));
+        assert_eq!(manager.roots[0].size, 100);
+        assert_eq!(manager.roots[1].size, 100);
+
+        // evicted as usual
+        manager.found(1, CacheFile::for_test(5, 100));
+        assert_eq!(manager.current_size(), 200);
+
+        manager.lost(0, &CacheFile::for_test(2, 100));
+        manager.lost(0, &CacheFile::for_test(5, 100));
+        assert_eq!(manager.current_size(), 100);
+        assert!(manager.roots[1].policy.contains(&CacheFile::for_test(5, 100).hash));
+
+        assert!(manager.indexed_hashes() == [(1, CacheFile::for_test(5, 100).hash)]);
+        let (root_dir, found) = manager.lookup(1, &CacheFile::for_test(5, 100).hash).unwrap();
+        assert_eq!(root_dir, dir.join("1"));
+        assert_eq!(found.filename(true), CacheFile::for_test(5, 100).filename(true));
+        assert!(manager.lookup(0, &CacheFile::for_test(5, 100).hash).is_none());
+    }
+}
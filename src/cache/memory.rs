@@ -0,0 +1,175 @@
+use std::time::SystemTime;
+
+use hyper::body::Bytes;
+
+use crate::utils::{LruItem, LruTable};
+
+use super::file::{CacheFile, FileHash};
+
+/// Files larger than this are never kept in memory
+const MAX_FILE_SIZE: u64 = 256 * 1024;
+/// Number of hits on disk for a file to be loaded into memory
+const ADMIT_HITS: u32 = 2;
+/// Number of files whose hits are tracked before loaded
+const MAX_CANDIDATES: usize = 4096;
+
+/// Small and frequently hit files kept in memory, in front of disk cache.
+///
+/// Files are evicted in LRU order within the memory budget. It's owned by
+/// [`CacheManager`](super::CacheManager), which removes files from here along with the index.
+pub struct MemoryTier {
+    budget: u64,
+    used: u64,
+    /// Disabled by `use_less_memory` setting
+    enabled: bool,
+    files: LruTable<MemoryFile>,
+    candidates: LruTable<Candidate>,
+    candidate_count: usize,
+}
+
+struct MemoryFile {
+    hash: FileHash,
+    data: Bytes,
+    modified: Option<SystemTime>,
+}
+
+impl LruItem for MemoryFile {
+    type Key = FileHash;
+
+    fn key_ref(&self) -> &Self::Key {
+        &self.hash
+    }
+}
+
+struct Candidate {
+    hash: FileHash,
+    hits: u32,
+}
+
+impl LruItem for Candidate {
+    type Key = FileHash;
+
+    fn key_ref(&self) -> &Self::Key {
+        &self.hash
+    }
+}
+
+impl MemoryTier {
+    pub fn new(budget: u64) -> MemoryTier {
+        MemoryTier {
+            budget,
+            used: 0,
+            enabled: true,
+            files: LruTable::new(),
+            candidates: LruTable::new(),
+            candidate_count: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.files = LruTable::new();
+            self.candidates = LruTable::new();
+            self.used = 0;
+            self.candidate_count = 0;
+        }
+        self.enabled = enabled;
+    }
+
+    fn is_active(&self) -> bool {
+        self.enabled && self.budget != 0
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// # Return
+    /// Content and modification time of file.
+    pub fn get(&mut self, hash: &FileHash) -> Option<(Bytes, Option<SystemTime>)> {
+        self.files.get(hash).map(|f| (f.data.clone(), f.modified))
+    }
+
+    /// Record a hit of file on disk.
+    ///
+    /// # Return
+    /// `true` if the file should be loaded into memory.
+    pub fn hit(&mut self, file: &CacheFile) -> bool {
+        let size = file.info.size;
+        if !self.is_active() || size == 0 || size > MAX_FILE_SIZE.min(self.budget) {
+            return false;
+        }
+
+        if let Some(candidate) = self.candidates.get(&file.hash) {
+            candidate.hits += 1;
+            if candidate.hits >= ADMIT_HITS {
+                self.candidates.remove(&file.hash);
+                self.candidate_count -= 1;
+                return true;
+            }
+            return false;
+        }
+
+        self.candidates.push_front(Candidate { hash: file.hash, hits: 1 });
+        self.candidate_count += 1;
+        if self.candidate_count > MAX_CANDIDATES {
+            self.candidates.pop_back();
+            self.candidate_count -= 1;
+        }
+        false
+    }
+
+    pub fn insert(&mut self, hash: FileHash, data: Bytes, modified: Option<SystemTime>) {
+        if !self.is_active() {
+            return;
+        }
+
+        self.remove(&hash);
+        while self.used + data.len() as u64 > self.budget {
+            let Some(file) = self.files.pop_back() else { break };
+            self.used -= file.data.len() as u64;
+        }
+        self.used += data.len() as u64;
+        self.files.push_front(MemoryFile { hash, data, modified });
+    }
+
+    pub fn remove(&mut self, hash: &FileHash) {
+        if let Some(file) = self.files.remove(hash) {
+            self.used -= file.data.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn memory_tier() {
+        let mut tier = MemoryTier::new(1000);
+        let (a, b, c) = (CacheFile::for_test(1, 400), CacheFile::for_test(2, 400), CacheFile::for_test(3, 400));
+
+        // loaded on the second hit
+        assert!(!tier.hit(&a));
+        assert!(tier.hit(&a));
+        assert!(!tier.hit(&CacheFile::for_test(4, 2000)));
+        assert!(!tier.hit(&CacheFile::for_test(4, 2000)));
+
+        for f in [&a, &b, &c] {
+            tier.insert(f.hash, Bytes::from(vec![0; 400]), None);
+        }
+        assert!(tier.get(&a.hash).is_none());
+        assert!(tier.get(&b.hash).is_some());
+        assert_eq!(tier.used(), 800);
+
+        tier.remove(&b.hash);
+        assert!(tier.get(&b.hash).is_none());
+        assert_eq!(tier.used(), 400);
+
+        tier.set_enabled(false);
+        assert_eq!(tier.used(), 0);
+        tier.insert(a.hash, Bytes::from(vec![0; 400]), None);
+        assert!(tier.get(&a.hash).is_none());
+        assert!(!tier.hit(&a));
+    }
+}
@@ -1,7 +1,23 @@
+mod admission;
+mod check;
 mod file;
+mod inflight;
 mod manager;
+mod memory;
+mod policy;
+mod scrub;
+mod snapshot;
 mod stream;
+mod warmup;
+#[cfg(target_os = "linux")]
+mod watch;
 
+pub use admission::AdmissionKind;
+pub use check::check;
 pub use file::CacheFile;
+pub use inflight::InFlight;
 pub use stream::CacheStream;
-pub use manager::CacheManager;
+pub use manager::{CacheManager, Placement, scan};
+pub use policy::PolicyKind;
+pub use snapshot::SNAPSHOT_FILE;
+pub use warmup::parse_list;
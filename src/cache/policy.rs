@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+
+use crate::utils::{LruItem, LruTable, SlabMap};
+
+use super::file::{CacheFile, FileHash, FileType, PackedFile};
+
+/// Decide which file is going to be evicted when cache is full.
+pub trait EvictionPolicy: Send {
+    fn name(&self) -> &'static str;
+
+    /// Insert a file, return the previous one with same hash if any.
+    fn insert(&mut self, file: CacheFile) -> Option<CacheFile>;
+
+    /// Record an access of file, return `false` if it's not present.
+    fn access(&mut self, hash: &FileHash) -> bool;
+
+    fn remove(&mut self, hash: &FileHash) -> Option<CacheFile>;
+
+    fn contains(&self, hash: &FileHash) -> bool;
+
+    /// Look up a file without recording an access.
+    fn get(&self, hash: &FileHash) -> Option<CacheFile>;
+
+    /// Pop the next file to be evicted.
+    fn evict(&mut self) -> Option<CacheFile>;
+
+    /// Iterate from the next file to be evicted.
+    fn iter(&self) -> Box<dyn Iterator<Item = CacheFile> + '_>;
+}
+
+/// Files are kept in index as [`PackedFile`], which costs about 55 bytes per file with `lru`,
+/// or about 100 bytes with `lfu` and `gdsf`. A file of unknown type takes about 60 bytes more for its extension.
+#[derive(Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyKind {
+    /// Least recently used
+    #[default]
+    Lru,
+    /// Least frequently used with dynamic aging
+    Lfu,
+    /// Greedy dual size frequency, which prefers to keep small files
+    Gdsf,
+}
+
+impl PolicyKind {
+    pub fn build(self) -> Box<dyn EvictionPolicy> {
+        match self {
+            PolicyKind::Lru => Box::new(Lru::default()),
+            PolicyKind::Lfu => Box::new(Priority::new(false)),
+            PolicyKind::Gdsf => Box::new(Priority::new(true)),
+        }
+    }
+}
+
+impl LruItem for PackedFile {
+    type Key = FileHash;
+
+    fn key_ref(&self) -> &Self::Key {
+        &self.hash
+    }
+}
+
+/// Extensions of indexed files of unknown type, which are not kept in [`PackedFile`].
+#[derive(Default)]
+struct UnknownTypes(HashMap<FileHash, Box<str>>);
+
+impl UnknownTypes {
+    fn pack(&mut self, file: &CacheFile) -> PackedFile {
+        if let FileType::Unknown(ext) = &file.info.typ {
+            self.0.insert(file.hash, ext.as_str().into());
+        }
+        PackedFile::from(file)
+    }
+
+    fn unpack(&self, file: &PackedFile) -> CacheFile {
+        file.unpack(self.0.get(&file.hash).map(|s| &**s))
+    }
+
+    /// Unpack a file removed from index.
+    fn take(&mut self, file: &PackedFile) -> CacheFile {
+        file.unpack(self.0.remove(&file.hash).as_deref())
+    }
+}
+
+pub struct Lru {
+    table: LruTable<PackedFile>,
+    unknown: UnknownTypes,
+}
+
+impl Default for Lru {
+    fn default() -> Self {
+        Lru { table: LruTable::new(), unknown: UnknownTypes::default() }
+    }
+}
+
+impl EvictionPolicy for Lru {
+    fn name(&self) -> &'static str {
+        "lru"
+    }
+
+    fn insert(&mut self, file: CacheFile) -> Option<CacheFile> {
+        let previous = self.remove(&file.hash);
+        self.table.push_front(self.unknown.pack(&file));
+        previous
+    }
+
+    fn access(&mut self, hash: &FileHash) -> bool {
+        self.table.get(hash).is_some()
+    }
+
+    fn remove(&mut self, hash: &FileHash) -> Option<CacheFile> {
+        self.table.remove(hash).map(|f| self.unknown.take(&f))
+    }
+
+    fn contains(&self, hash: &FileHash) -> bool {
+        self.table.contains(hash)
+    }
+
+    fn get(&self, hash: &FileHash) -> Option<CacheFile> {
+        self.table.peek(hash).map(|f| self.unknown.unpack(f))
+    }
+
+    fn evict(&mut self) -> Option<CacheFile> {
+        self.table.pop_back().map(|f| self.unknown.take(&f))
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = CacheFile> + '_> {
+        Box::new(self.table.iter().rev().map(|f| self.unknown.unpack(f)))
+    }
+}
+
+/// Scale of frequency per byte in GDSF, so that it could be kept in integer.
+const GDSF_SCALE: u64 = 1 << 32;
+
+/// Greedy dual family policy.
+///
+/// Each file has a priority of `clock + weight`, and the one with lowest priority is evicted.
+/// `clock` is set to the priority of the last evicted file, so that files which are frequently
+/// accessed long ago would be aged out eventually.
+///
+/// `weight` is the access frequency for LFU, or frequency per byte for GDSF.
+pub struct Priority {
+    size_aware: bool,
+    clock: u64,
+    /// Tie breaker of same priority, which makes it LRU among them.
+    seq: u64,
+    entries: SlabMap<PriorityEntry>,
+    /// Index of entries in order of priority
+    queue: BTreeMap<(u64, u64), u32>,
+    unknown: UnknownTypes,
+}
+
+struct PriorityEntry {
+    file: PackedFile,
+    freq: u64,
+    key: (u64, u64),
+}
+
+impl LruItem for PriorityEntry {
+    type Key = FileHash;
+
+    fn key_ref(&self) -> &Self::Key {
+        &self.file.hash
+    }
+}
+
+impl Priority {
+    pub fn new(size_aware: bool) -> Priority {
+        Priority {
+            size_aware,
+            clock: 0,
+            seq: 0,
+            entries: SlabMap::new(),
+            queue: BTreeMap::new(),
+            unknown: UnknownTypes::default(),
+        }
+    }
+
+    fn key(&mut self, freq: u64, size: u64) -> (u64, u64) {
+        let weight = match self.size_aware {
+            true => freq.saturating_mul(GDSF_SCALE) / size.max(1),
+            false => freq,
+        };
+        self.seq += 1;
+        (self.clock.saturating_add(weight), self.seq)
+    }
+
+    /// Remove entry at `index`, its key should be removed from queue by the caller.
+    fn remove_at(&mut self, index: u32) -> CacheFile {
+        let entry = self.entries.remove_at(index);
+        // the last entry is moved into `index`
+        if (index as usize) < self.entries.len() {
+            let key = self.entries.get(index).key;
+            self.queue.insert(key, index);
+        }
+        self.unknown.take(&entry.file)
+    }
+}
+
+impl EvictionPolicy for Priority {
+    fn name(&self) -> &'static str {
+        match self.size_aware {
+            true => "gdsf",
+            false => "lfu",
+        }
+    }
+
+    fn insert(&mut self, file: CacheFile) -> Option<CacheFile> {
+        let previous = self.remove(&file.hash);
+        let freq = previous.as_ref().map_or(1, |_| 2);
+        let key = self.key(freq, file.info.size);
+        let file = self.unknown.pack(&file);
+        let index = self.entries.insert(PriorityEntry { file, freq, key });
+        self.queue.insert(key, index);
+        previous
+    }
+
+    fn access(&mut self, hash: &FileHash) -> bool {
+        let Some(index) = self.entries.find(hash) else { return false };
+        let entry = self.entries.get(index);
+        let (freq, size, old_key) = (entry.freq.saturating_add(1), entry.file.size(), entry.key);
+
+        let key = self.key(freq, size);
+        self.queue.remove(&old_key);
+        self.queue.insert(key, index);
+        let entry = self.entries.get_mut(index);
+        entry.freq = freq;
+        entry.key = key;
+        true
+    }
+
+    fn remove(&mut self, hash: &FileHash) -> Option<CacheFile> {
+        let index = self.entries.find(hash)?;
+        self.queue.remove(&self.entries.get(index).key);
+        Some(self.remove_at(index))
+    }
+
+    fn contains(&self, hash: &FileHash) -> bool {
+        self.entries.find(hash).is_some()
+    }
+
+    fn get(&self, hash: &FileHash) -> Option<CacheFile> {
+        let index = self.entries.find(hash)?;
+        Some(self.unknown.unpack(&self.entries.get(index).file))
+    }
+
+    fn evict(&mut self) -> Option<CacheFile> {
+        let ((priority, _), index) = self.queue.pop_first()?;
+        self.clock = priority;
+        Some(self.remove_at(index))
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = CacheFile> + '_> {
+        Box::new(self.queue.values().map(|i| self.unknown.unpack(&self.entries.get(*i).file)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn evict_order(policy: &mut dyn EvictionPolicy) -> Vec<u8> {
+        std::iter::from_fn(|| policy.evict()).map(|f| f.static_range() as u8).collect()
+    }
+
+    #[test]
+    fn lfu() {
+        let mut policy = PolicyKind::Lfu.build();
+        for n in 1..=4 {
+            policy.insert(CacheFile::for_test(n, 100));
+        }
+        policy.access(&CacheFile::for_test(1, 100).hash);
+        policy.access(&CacheFile::for_test(1, 100).hash);
+        policy.access(&CacheFile::for_test(3, 100).hash);
+        assert_eq!(evict_order(policy.as_mut()), [2, 4, 3, 1]);
+    }
+
+    #[test]
+    fn lfu_aging() {
+        let id = |f: Option<CacheFile>| f.map(|f| f.static_range() as u8);
+
+        let mut policy = PolicyKind::Lfu.build();
+        policy.insert(CacheFile::for_test(1, 100));
+        for _ in 0..3 {
+            policy.access(&CacheFile::for_test(1, 100).hash);
+        }
+        policy.insert(CacheFile::for_test(2, 100));
+        policy.insert(CacheFile::for_test(3, 100));
+        assert_eq!(id(policy.evict()), Some(2));
+        assert_eq!(id(policy.evict()), Some(3));
+
+        // the clock is raised by evictions, so a new file outweighs an old one with the same accesses
+        policy.insert(CacheFile::for_test(4, 100));
+        for _ in 0..3 {
+            policy.access(&CacheFile::for_test(4, 100).hash);
+        }
+        assert_eq!(evict_order(policy.as_mut()), [1, 4]);
+    }
+
+    #[test]
+    fn gdsf() {
+        let mut policy = PolicyKind::Gdsf.build();
+        policy.insert(CacheFile::for_test(1, 100));
+        policy.insert(CacheFile::for_test(2, 10000));
+        policy.insert(CacheFile::for_test(3, 1000));
+        policy.access(&CacheFile::for_test(2, 10000).hash);
+        assert_eq!(evict_order(policy.as_mut()), [2, 3, 1]);
+    }
+
+    #[test]
+    fn compact_index() {
+        assert_eq!(size_of::<PackedFile>(), 40);
+        assert_eq!(size_of::<PriorityEntry>(), 64);
+
+        for kind in [PolicyKind::Lru, PolicyKind::Gdsf] {
+            let mut policy = kind.build();
+            for n in 1..=4 {
+                policy.insert(CacheFile::for_test(n, 100));
+            }
+            let unknown = format!("{:x}-100-0-0-bin", FileHash::from([5; 20]));
+            policy.insert(CacheFile::try_from(unknown.as_str()).ok().unwrap());
+
+            // the last entry is moved into the removed one
+            assert!(policy.remove(&CacheFile::for_test(2, 100).hash).is_some());
+            assert!(policy.access(&CacheFile::for_test(1, 100).hash));
+            assert!(!policy.contains(&CacheFile::for_test(2, 100).hash));
+            let names: Vec<_> = policy.iter().map(|f| f.filename(false)).collect();
+            assert_eq!(names[2], unknown.replace("-bin", ".bin"));
+            assert_eq!(evict_order(policy.as_mut()), [3, 4, 5, 1]);
+        }
+    }
+}
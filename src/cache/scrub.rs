@@ -0,0 +1,128 @@
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::AppContext;
+use crate::utils::{Limiter, file_sha1, write_atomic};
+
+use super::file::{CacheFile, FileHash};
+
+/// File name of scrubber progress in data directory, which holds the last checked hash
+const SCRUB_PROGRESS_FILE: &str = "scrub_progress";
+
+/// Number of files looked up in index at once
+const BATCH_SIZE: usize = 256;
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+const PASS_INTERVAL: Duration = Duration::from_secs(86400);
+
+impl AppContext {
+    /// Re-hash cached files in order of hash, and drop those no longer matching their hash.
+    ///
+    /// Reading is throttled to the configured speed, and progress is saved in data directory,
+    /// so that an interrupted pass is resumed after restart.
+    pub async fn scrub_cache(&self) {
+        let Some(speed) = self.scrub_speed else { return };
+        let limiter = Limiter::new((speed as u64 * 1024) as f64);
+        let progress = self.data_dir.join(SCRUB_PROGRESS_FILE);
+
+        let mut cursor = load_progress(&progress).await;
+        loop {
+            let (mut checked, mut corrupted) = (0, 0);
+            let mut saved = Instant::now();
+
+            // files added during the pass are checked in the next one
+            let mut hashes = self.cache_manager.lock().unwrap().indexed_hashes();
+            hashes.sort_unstable_by_key(|(_, hash)| *hash);
+            let start = cursor.map_or(0, |c| hashes.partition_point(|(_, hash)| *hash <= c));
+
+            for batch in hashes[start..].chunks(BATCH_SIZE) {
+                let files: Vec<_> = {
+                    let manager = self.cache_manager.lock().unwrap();
+                    // removed since the pass started
+                    batch.iter().filter_map(|(root, hash)| manager.lookup(*root, hash)).collect()
+                };
+
+                for (dir, file) in files {
+                    match verify_file(&limiter, &file, &dir).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            corrupted += 1;
+                            self.drop_corrupted(&file, &dir).await;
+                        }
+                        // removed since taken from index
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => log::error!("scrub file: {}: {}", file.filename(false), e),
+                    }
+                    checked += 1;
+                }
+                cursor = batch.last().map(|(_, hash)| *hash);
+
+                if saved.elapsed() >= SAVE_INTERVAL {
+                    save_progress(&progress, cursor.as_ref()).await;
+                    saved = Instant::now();
+                }
+            }
+
+            log::info!("cache scrub pass finished, {} files checked, {} corrupted", checked, corrupted);
+            cursor = None;
+            save_progress(&progress, None).await;
+            tokio::time::sleep(PASS_INTERVAL).await;
+        }
+    }
+
+    /// Remove a corrupted file from index, and move it into quarantine directory or delete it.
+    async fn drop_corrupted(&self, file: &CacheFile, cache_dir: &Path) {
+        self.cache_manager.lock().unwrap().remove(file);
+
+        let path = file.path(cache_dir);
+        let name = file.filename(false);
+        if let Some(dir) = &self.quarantine_dir {
+            match quarantine(&path, dir).await {
+                Ok(()) => {
+                    log::warn!("corrupted file moved into quarantine: {}", name);
+                    return;
+                }
+                // e.g. quarantine directory is on another filesystem
+                Err(e) => log::error!("unable to quarantine file: {}: {}", name, e),
+            }
+        }
+
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => log::warn!("corrupted file removed: {}", name),
+            Err(e) => log::error!("unable to remove file: {}: {}", name, e),
+        }
+    }
+}
+
+/// # Return
+/// `false` if file size or hash does not match its name.
+async fn verify_file(limiter: &Limiter, file: &CacheFile, cache_dir: &Path) -> io::Result<bool> {
+    let f = tokio::fs::File::open(file.path(cache_dir)).await?;
+    if f.metadata().await?.len() != file.info.size {
+        return Ok(false);
+    }
+
+    let hash = file_sha1(&mut limiter.limit(f)).await?;
+    Ok(hash == format!("{:x}", file.hash))
+}
+
+/// Move a file into quarantine directory, keeping its file name.
+pub(super) async fn quarantine(path: &Path, dir: &Path) -> io::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let name = path.file_name().ok_or(io::ErrorKind::InvalidInput)?;
+    tokio::fs::rename(path, dir.join(name)).await
+}
+
+async fn load_progress(path: &Path) -> Option<FileHash> {
+    let data = tokio::fs::read_to_string(path).await.ok()?;
+    FileHash::try_from(data.trim()).ok()
+}
+
+async fn save_progress(path: &Path, cursor: Option<&FileHash>) {
+    let data = cursor.map(|h| format!("{:x}", h)).unwrap_or_default();
+    if let Err(e) = write_atomic(path, data.as_bytes()).await {
+        log::error!("save scrub progress: {}", e);
+    }
+}
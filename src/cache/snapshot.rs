@@ -0,0 +1,142 @@
+use std::borrow::Borrow;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::file::{CacheFile, FileHash, FileInfo, FileType};
+
+/// File name of index snapshot in data directory
+pub const SNAPSHOT_FILE: &str = "cache_index";
+
+const MAGIC: &[u8; 8] = b"HATHIDX\x02";
+
+/// Cache index saved on disk, so that we don't need to walk through cache directory on startup.
+///
+/// Layout in little endian:
+/// - header: magic, snapshot time in nanoseconds (u64), file count (u64), cache directory length (u16), cache directory
+/// - entries: hash (20 bytes), size (u64), x res (u32), y res (u32), extension length (u8), extension
+///
+/// Entries are in order of eviction.
+pub struct Snapshot {
+    /// Cache directory the snapshot is taken from, it's not used for other directories.
+    pub dir: PathBuf,
+    /// Shard directories modified after this time is out of date.
+    pub time: SystemTime,
+    pub files: Vec<CacheFile>,
+}
+
+impl Snapshot {
+    pub fn encode<I, F>(dir: &Path, time: SystemTime, files: I) -> Vec<u8>
+    where
+        I: Iterator<Item = F>,
+        F: Borrow<CacheFile>,
+    {
+        let nanos = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        let dir = dir.to_string_lossy();
+        let dir = &dir.as_bytes()[..dir.len().min(u16::MAX as usize)];
+
+        let mut buf = Vec::with_capacity(26 + dir.len() + files.size_hint().0 * 44);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&nanos.to_le_bytes());
+        buf.extend_from_slice(&0u64.to_le_bytes());
+        buf.extend_from_slice(&(dir.len() as u16).to_le_bytes());
+        buf.extend_from_slice(dir);
+
+        let mut written = 0u64;
+        for file in files {
+            let file = file.borrow();
+            let ext = file.info.typ.extension().as_bytes();
+            if ext.len() > u8::MAX as usize {
+                continue;
+            }
+            written += 1;
+            buf.extend_from_slice(file.hash.as_bytes());
+            buf.extend_from_slice(&file.info.size.to_le_bytes());
+            buf.extend_from_slice(&file.info.res.0.to_le_bytes());
+            buf.extend_from_slice(&file.info.res.1.to_le_bytes());
+            buf.push(ext.len() as u8);
+            buf.extend_from_slice(ext);
+        }
+        buf[16..24].copy_from_slice(&written.to_le_bytes());
+        buf
+    }
+
+    /// Return `None` if data is not a valid snapshot.
+    pub fn decode(data: &[u8]) -> Option<Snapshot> {
+        let mut reader = Reader(data);
+        if reader.take(8)? != MAGIC {
+            return None;
+        }
+        let time = UNIX_EPOCH + Duration::from_nanos(reader.u64()?);
+        let count = reader.u64()? as usize;
+        let len = u16::from_le_bytes(reader.take(2)?.try_into().unwrap()) as usize;
+        let dir = PathBuf::from(std::str::from_utf8(reader.take(len)?).ok()?);
+
+        // count is not trusted before all entries are read
+        let mut files = Vec::with_capacity(count.min(data.len() / 37));
+        for _ in 0..count {
+            let hash = FileHash::from(<[u8; 20]>::try_from(reader.take(20)?).ok()?);
+            let size = reader.u64()?;
+            let res = (reader.u32()?, reader.u32()?);
+            let len = reader.take(1)?[0] as usize;
+            let typ = FileType::from(std::str::from_utf8(reader.take(len)?).ok()?);
+            files.push(CacheFile { hash, info: FileInfo { size, res, typ } });
+        }
+
+        reader.0.is_empty().then_some(Snapshot { dir, time, files })
+    }
+
+    /// Whether the snapshot is taken from `cache_dir`, which may be given in another form.
+    pub fn is_of(&self, cache_dir: &Path) -> bool {
+        match (self.dir.canonicalize(), cache_dir.canonicalize()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => self.dir == cache_dir,
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let files = [
+            CacheFile::from_filename("5eb2e462781a2ba02cf435d6baa3573f4551c1a5-37444-1800-1000.png").unwrap(),
+            CacheFile::from_filename("0123456789abcdef0123456789abcdef01234567-1024-0-0.mkv").unwrap(),
+        ];
+        let time = UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789);
+
+        let dir = std::env::temp_dir();
+        let data = Snapshot::encode(&dir, time, files.iter());
+        let snapshot = Snapshot::decode(&data).unwrap();
+        assert_eq!(snapshot.time, time);
+        assert!(snapshot.is_of(&dir.join(".")));
+        assert!(!snapshot.is_of(&dir.join("hath-test-other-root")));
+        let names: Vec<_> = snapshot.files.iter().map(|f| f.filename(false)).collect();
+        assert_eq!(names, files.iter().map(|f| f.filename(false)).collect::<Vec<_>>());
+
+        assert!(Snapshot::decode(&data[..data.len() - 1]).is_none());
+        assert!(Snapshot::decode(b"HATHIDX\x01").is_none());
+    }
+}
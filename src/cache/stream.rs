@@ -1,86 +1,317 @@
-use std::mem::MaybeUninit;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::task::{self, Poll, ready};
+use std::time::SystemTime;
 
+use bytes::BytesMut;
 use http_body_util::BodyExt;
 use hyper::body::{Body, Bytes, Frame, SizeHint};
+use openssl::sha::Sha1;
 use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
-use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+use tokio::io::AsyncWriteExt;
 
+use crate::utils::{IoPool, Task, read_at};
 use crate::{AppContext, Error, Result};
 
-use super::CacheFile;
+use super::{CacheFile, CacheManager};
+use super::file::FileHash;
+use super::inflight::Fetch;
+
+/// Size of each read on cache hit
+const CHUNK_SIZE: usize = 256 * 1024;
+/// Capacity of read buffer, chunks are split from it and it's reused once they are sent
+const BUFFER_SIZE: usize = 4 * CHUNK_SIZE;
 
 pub enum CacheStream {
     Hit {
-        file: File,
-        len: u64,
-        buf: ReadBuf<'static>,
+        file: Arc<std::fs::File>,
+        pool: IoPool,
+        buf: BytesMut,
+        /// Offset of the next read
+        offset: u64,
+        end: u64,
+        /// Read of the next chunk, which is issued as soon as the previous one is received
+        pending: Option<Task<BytesMut>>,
+        modified: Option<SystemTime>,
+        _lease: Option<ReadLease>,
+    },
+    /// Cache hit served from memory tier
+    Memory {
+        data: Bytes,
+        modified: Option<SystemTime>,
     },
     Miss {
-        rx: UnboundedReceiver<Bytes>,
-        size: SizeHint,
+        fetch: Arc<Fetch>,
+        index: usize,
+        /// Offset of the next chunk in file
+        offset: u64,
+        range: Range<u64>,
     },
 }
 
 impl CacheStream {
-    pub async fn new(ctx: &AppContext, file_info: &CacheFile, extra: (&str, &str)) -> Result<Option<CacheStream>> {
-        let path = file_info.path(&ctx.cache_dir);
+    /// Create a stream of `range` of the file, the range should be inside the file size.
+    pub async fn new(
+        ctx: &Arc<AppContext>,
+        file_info: &CacheFile,
+        extra: (&str, &str),
+        range: Range<u64>,
+    ) -> Result<Option<CacheStream>> {
+        let memory = ctx.cache_manager.lock().unwrap().get_memory(file_info);
+        if let Some((data, modified)) = memory {
+            let data = data.slice(range.start as usize..range.end as usize);
+            return Ok(Some(CacheStream::Memory { data, modified }));
+        }
+
+        let paths = ctx.cache_manager.lock().unwrap().locate(file_info);
+        let opened = ctx.io_pool.run(move || {
+            let mut errors = Vec::new();
+            for (root, path) in paths {
+                let opened = std::fs::File::open(&path).and_then(|f| Ok((f.metadata()?, f)));
+                match opened {
+                    Ok((metadata, file)) => return Ok((Some((file, metadata)), errors)),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => errors.push((root, e)),
+                }
+            }
+            Ok((None, errors))
+        });
+        let (file, errors) = opened.await?;
+        for (root, e) in errors {
+            log::error!("open cache file: {}: {}", file_info.filename(false), e);
+            ctx.cache_manager.lock().unwrap().io_error(root, &e);
+        }
 
-        let _ = tokio::fs::create_dir_all(path.parent().unwrap()).await;
-        let mut file = OpenOptions::new().create(true).read(true).append(true).open(&path).await?;
+        // join the running fetch if any
+        if let Some(fetch) = ctx.in_flight.get(&file_info.hash) {
+            ctx.cache_manager.lock().unwrap().miss(file_info);
+            return Ok(CacheStream::subscribe(fetch, range).await);
+        }
 
-        let metadata = file.metadata().await?;
-        let len = metadata.len();
+        if let Some((file, metadata)) = file
+            && metadata.len() != 0
+        {
+            let file = Arc::new(file);
+            let modified = metadata.modified().ok();
+            let mut manager = ctx.cache_manager.lock().unwrap();
+            if manager.update(file_info) {
+                load_memory(ctx, file.clone(), file_info, modified);
+            }
+            manager.acquire(&file_info.hash);
+            drop(manager);
 
-        if len == 0 {
-            let mut body =
-                if let Some(body) = ctx.static_range_fetch(extra.0, extra.1, &file_info.filename(true)).await? {
-                    body
-                } else {
-                    return Ok(None);
-                };
-            let size = body.size_hint();
+            let lease = ReadLease { ctx: ctx.clone(), hash: file_info.hash };
+            return Ok(Some(CacheStream::hit(&ctx.io_pool, file, range, modified, Some(lease))));
+        }
 
-            let (tx, rx) = unbounded_channel();
+        ctx.cache_manager.lock().unwrap().miss(file_info);
+        let (fetch, is_new) = ctx.in_flight.get_or_insert(file_info.hash);
+        if is_new {
+            let ctx = ctx.clone();
+            let fetch = fetch.clone();
+            let file_info = file_info.clone();
+            let (fileindex, xres) = (extra.0.to_owned(), extra.1.to_owned());
             tokio::spawn(async move {
-                while let Some(f) = body.frame().await {
-                    match f {
-                        Ok(f) => {
-                            // if it's data frame, get the data
-                            if let Ok(b) = f.into_data() {
-                                // ignore error even rx closed
-                                let _ = tx.send(b.clone());
-
-                                if let Err(e) = file.write_all(&b).await {
-                                    log::error!("write cache file: {}", e);
-                                    let _ = file.set_len(0).await;
-                                    break;
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            // when error occured, the file is most likely be broken
-                            log::error!("read remote cache stream: {}", e);
-                            let _ = file.set_len(0).await;
-                            break;
+                // fetch is finished and removed even if the task panics
+                let _guard = ctx.in_flight.guard(file_info.hash, fetch.clone());
+                fetch_to_cache(&ctx, &fetch, &file_info, &fileindex, &xres).await;
+            });
+        }
+        Ok(CacheStream::subscribe(fetch, range).await)
+    }
+
+    fn hit(
+        pool: &IoPool,
+        file: Arc<std::fs::File>,
+        range: Range<u64>,
+        modified: Option<SystemTime>,
+        lease: Option<ReadLease>,
+    ) -> CacheStream {
+        // most files are small enough to be read at once
+        let capacity = (range.end - range.start).min(BUFFER_SIZE as u64) as usize;
+        CacheStream::Hit {
+            file,
+            pool: pool.clone(),
+            buf: BytesMut::with_capacity(capacity),
+            offset: range.start,
+            end: range.end,
+            pending: None,
+            modified,
+            _lease: lease,
+        }
+    }
+
+    async fn subscribe(fetch: Arc<Fetch>, range: Range<u64>) -> Option<CacheStream> {
+        if !fetch.ready().await {
+            return None;
+        }
+
+        Some(CacheStream::Miss { fetch, index: 0, offset: 0, range })
+    }
+
+    /// Modification time of cached file, `None` if it's not cached yet.
+    pub fn modified(&self) -> Option<SystemTime> {
+        match self {
+            CacheStream::Hit { modified, .. } | CacheStream::Memory { modified, .. } => *modified,
+            CacheStream::Miss { .. } => None,
+        }
+    }
+}
+
+/// Keep file on disk while it's read, see [`CacheManager::acquire`](super::CacheManager::acquire).
+pub struct ReadLease {
+    ctx: Arc<AppContext>,
+    hash: FileHash,
+}
+
+impl Drop for ReadLease {
+    fn drop(&mut self) {
+        self.ctx.cache_manager.lock().unwrap().release(&self.hash);
+    }
+}
+
+/// Read the whole file in background, and keep it in memory tier.
+fn load_memory(ctx: &Arc<AppContext>, file: Arc<std::fs::File>, file_info: &CacheFile, modified: Option<SystemTime>) {
+    let ctx = ctx.clone();
+    let file_info = file_info.clone();
+    let len = file_info.info.size as usize;
+    tokio::spawn(async move {
+        let read = ctx.io_pool.run(move || {
+            let mut buf = vec![0; len];
+            let n = read_at(&file, &mut buf, 0)?;
+            buf.truncate(n);
+            Ok(buf)
+        });
+        match read.await {
+            Ok(data) => ctx.cache_manager.lock().unwrap().load_memory(&file_info, data.into(), modified),
+            Err(e) => log::error!("load file into memory: {}: {}", file_info.filename(false), e),
+        }
+    });
+}
+
+/// Fetch file from upstream, and move it into cache once it's verified.
+async fn fetch_to_cache(ctx: &AppContext, fetch: &Fetch, file_info: &CacheFile, fileindex: &str, xres: &str) {
+    let body = match ctx.static_range_fetch(fileindex, xres, &file_info.filename(true)).await {
+        Ok(Some(body)) => body,
+        Ok(None) => return fetch.finish(false),
+        Err(e) => {
+            log::error!("fetch {}: {}", file_info.filename(false), e);
+            return fetch.finish(false);
+        }
+    };
+    receive(&ctx.cache_manager, fetch, file_info, body).await;
+}
+
+/// Send `body` to subscribers of `fetch` while writing it into cache, and keep it only if it's verified.
+async fn receive<B>(cache_manager: &Mutex<CacheManager>, fetch: &Fetch, file_info: &CacheFile, mut body: B)
+where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: fmt::Display,
+{
+    // file is only sent to client if no root is in service, or it's not admitted
+    let place = {
+        let mut manager = cache_manager.lock().unwrap();
+        manager.place(file_info).filter(|(root, _)| manager.admit(*root, file_info))
+    };
+    let mut file = match &place {
+        Some((root, dir)) => match create_temp(&file_info.temp_path(dir)).await {
+            Ok(file) => Some(file),
+            Err(e) => {
+                log::error!("open cache file: {}", e);
+                cache_manager.lock().unwrap().write_error(*root, &e);
+                None
+            }
+        },
+        None => None,
+    };
+    fetch.start();
+
+    let mut hasher = Sha1::new();
+    let mut received = 0;
+    while let Some(f) = body.frame().await {
+        match f {
+            Ok(f) => {
+                // if it's data frame, get the data
+                if let Ok(b) = f.into_data() {
+                    hasher.update(&b);
+                    received += b.len() as u64;
+
+                    if let Some(w) = &mut file
+                        && let Err(e) = w.write_all(&b).await
+                    {
+                        log::error!("write cache file: {}", e);
+                        if let Some((root, _)) = &place {
+                            cache_manager.lock().unwrap().write_error(*root, &e);
                         }
+                        file = None;
                     }
+                    fetch.push(b);
                 }
-            });
+            }
+            Err(e) => {
+                // when error occured, the file is most likely be broken
+                log::error!("read remote cache stream: {}", e);
+                break;
+            }
+        }
+    }
+
+    let verified = received == file_info.info.size && FileHash::from(hasher.finish()) == file_info.hash;
+    fetch.finish(verified);
 
-            ctx.cache_manager.lock().unwrap().add(&ctx.cache_dir, file_info.clone());
-            return Ok(Some(CacheStream::Miss { rx, size }));
+    if !verified {
+        let mut manager = cache_manager.lock().unwrap();
+        manager.reject(file_info);
+        log::warn!(
+            "rejected {}: got {} bytes with mismatched hash or size, {} rejected in total",
+            file_info.filename(false),
+            received,
+            manager.rejected()
+        );
+    }
+
+    let Some((root, dir)) = place else { return };
+    let path = file_info.temp_path(&dir);
+    if let Some(file) = file
+        && verified
+    {
+        match commit(file, &path, &file_info.path(&dir)).await {
+            Ok(()) => {
+                cache_manager.lock().unwrap().add(root, file_info.clone());
+                return;
+            }
+            Err(e) => {
+                log::error!("commit cache file: {}: {}", file_info.filename(false), e);
+                cache_manager.lock().unwrap().write_error(root, &e);
+            }
         }
+    }
 
-        ctx.cache_manager.lock().unwrap().update(file_info);
-        let buf = vec![MaybeUninit::uninit(); 8192].leak();
-        let buf = ReadBuf::uninit(buf);
-        Ok(Some(CacheStream::Hit { file, len, buf }))
+    if let Err(e) = tokio::fs::remove_file(&path).await
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        log::error!("unable to remove file: {}: {}", path.display(), e);
     }
 }
 
+async fn create_temp(path: &Path) -> io::Result<File> {
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    OpenOptions::new().create(true).write(true).truncate(true).open(path).await
+}
+
+/// Flush temporary file to disk and move it into place.
+async fn commit(mut file: File, temp: &Path, path: &Path) -> io::Result<()> {
+    file.flush().await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(temp, path).await
+}
+
 impl Body for CacheStream {
     type Data = Bytes;
 
@@ -92,43 +323,188 @@ impl Body for CacheStream {
     ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
         let this = self.get_mut();
         match this {
-            CacheStream::Hit { file, buf, .. } => {
-                ready!(Pin::new(file).poll_read(cx, buf)?);
-
-                if !buf.filled().is_empty() {
-                    let bytes = Bytes::copy_from_slice(buf.filled());
-                    let frame = Frame::data(bytes);
-                    buf.clear();
-                    Poll::Ready(Some(Ok(frame)))
-                } else {
-                    Poll::Ready(None)
+            CacheStream::Hit { file, pool, buf, offset, end, pending, .. } => {
+                if *offset >= *end {
+                    return Poll::Ready(None);
                 }
-            }
-            CacheStream::Miss { rx, .. } => {
-                if let Some(b) = ready!(rx.poll_recv(cx)) {
-                    let frame = Frame::data(b);
-                    return Poll::Ready(Some(Ok(frame)));
+                let task = pending.get_or_insert_with(|| read_chunk(pool, file, std::mem::take(buf), *offset, *end));
+                let result = ready!(Pin::new(task).poll(cx));
+                *pending = None;
+
+                let mut chunk = result?;
+                if chunk.is_empty() {
+                    // file is truncated
+                    return Poll::Ready(None);
                 }
+                let data = chunk.split().freeze();
+                *offset += data.len() as u64;
 
-                Poll::Ready(None)
+                // read ahead while this chunk is being sent
+                if *offset < *end {
+                    *pending = Some(read_chunk(pool, file, chunk, *offset, *end));
+                }
+                Poll::Ready(Some(Ok(Frame::data(data))))
             }
+            CacheStream::Memory { data, .. } => match data.is_empty() {
+                true => Poll::Ready(None),
+                false => Poll::Ready(Some(Ok(Frame::data(std::mem::take(data))))),
+            },
+            CacheStream::Miss { fetch, index, offset, range } => loop {
+                if *offset >= range.end {
+                    return Poll::Ready(None);
+                }
+
+                let bytes = match ready!(fetch.poll_chunk(cx, *index)) {
+                    Some(Ok(bytes)) => bytes,
+                    ret => return Poll::Ready(ret.map(|r| r.map(Frame::data))),
+                };
+                let start = *offset;
+                *index += 1;
+                *offset += bytes.len() as u64;
+
+                // skip chunks before requested range
+                if *offset <= range.start {
+                    continue;
+                }
+                let from = range.start.saturating_sub(start) as usize;
+                let to = (range.end.min(*offset) - start) as usize;
+                return Poll::Ready(Some(Ok(Frame::data(bytes.slice(from..to)))));
+            },
         }
     }
 
     fn size_hint(&self) -> SizeHint {
         match self {
-            CacheStream::Hit { len, .. } => SizeHint::with_exact(*len),
-            CacheStream::Miss { size, .. } => size.clone(),
+            CacheStream::Hit { offset, end, .. } => SizeHint::with_exact(end.saturating_sub(*offset)),
+            CacheStream::Memory { data, .. } => SizeHint::with_exact(data.len() as u64),
+            CacheStream::Miss { offset, range, .. } => SizeHint::with_exact(range.end.saturating_sub(range.start.max(*offset))),
         }
     }
 }
 
-impl Drop for CacheStream {
-    fn drop(&mut self) {
-        if let CacheStream::Hit { buf, .. } = self {
-            // SAFETY: buf is static owned by current struct which is dropping
-            let buf = unsafe { Box::from_raw(buf.inner_mut() as *mut _) };
-            drop(buf);
+/// Read the chunk at `offset` on I/O pool.
+///
+/// Data is read into the spare capacity of `buf`, whose allocation is reclaimed once all
+/// chunks split from it are dropped.
+fn read_chunk(pool: &IoPool, file: &Arc<std::fs::File>, mut buf: BytesMut, offset: u64, end: u64) -> Task<BytesMut> {
+    let file = file.clone();
+    let len = (end - offset).min(CHUNK_SIZE as u64) as usize;
+    pool.run(move || {
+        buf.reserve(len);
+        buf.resize(len, 0);
+        let n = read_at(&file, &mut buf, offset)?;
+        buf.truncate(n);
+        Ok(buf)
+    })
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::future::poll_fn;
+    use std::hint::black_box;
+    use std::time::{Duration, Instant};
+
+    use http_body_util::Full;
+    use tokio::io::AsyncReadExt;
+
+    use crate::cache::{Placement, PolicyKind};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reject_corrupted() {
+        let dir = std::env::temp_dir().join("hath-test-receive");
+        let _ = std::fs::remove_dir_all(&dir);
+        let manager = Mutex::new(CacheManager::new(PolicyKind::Lru, vec![(dir.clone(), None)], Placement::Hash));
+        manager.lock().unwrap().set_max_size(1 << 20);
+        manager.lock().unwrap().load(vec![Ok(Vec::new())]);
+
+        let data = Bytes::from_static(b"hentai@home");
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        let name = format!("{:x}-{}-0-0.jpg", FileHash::from(hasher.finish()), data.len());
+        let file = CacheFile::from_filename(&name).unwrap();
+
+        // same size with mismatched hash, and truncated
+        for body in [Bytes::from_static(b"hentai@hone"), data.slice(..6)] {
+            let fetch = Fetch::default();
+            receive(&manager, &fetch, &file, Full::new(body)).await;
+            // subscribers would not take it as a complete file
+            assert!(matches!(poll_fn(|cx| fetch.poll_chunk(cx, 1)).await, Some(Err(Error::CorruptedFile))));
+            assert!(!file.temp_path(&dir).exists());
+            assert!(!file.path(&dir).exists());
+            assert!(!manager.lock().unwrap().contains(&file));
         }
+        assert_eq!(manager.lock().unwrap().rejected(), 2);
+
+        let fetch = Fetch::default();
+        receive(&manager, &fetch, &file, Full::new(data.clone())).await;
+        assert!(poll_fn(|cx| fetch.poll_chunk(cx, 1)).await.is_none());
+        assert!(manager.lock().unwrap().contains(&file));
+        assert_eq!(std::fs::read(file.path(&dir)).unwrap(), data);
+        assert_eq!(manager.lock().unwrap().rejected(), 2);
+    }
+
+    /// CPU time of the process in all threads.
+    fn cpu_time() -> Duration {
+        let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+        unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+        let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+        time(usage.ru_utime) + time(usage.ru_stime)
+    }
+
+    async fn measure(name: &str, f: impl Future<Output = usize>) {
+        let (wall, cpu) = (Instant::now(), cpu_time());
+        let bytes = f.await as f64;
+        let (wall, cpu) = (wall.elapsed().as_secs_f64(), (cpu_time() - cpu).as_secs_f64());
+        let gib = bytes / (1 << 30) as f64;
+        println!("{}: {:.0} MiB/s, {:.0} ms CPU per GiB", name, gib * 1024.0 / wall, cpu * 1000.0 / gib);
+    }
+
+    /// Throughput of serving a hit from page cache.
+    ///
+    /// Run with `cargo test --release hit_throughput -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn hit_throughput() {
+        const SIZE: usize = 64 << 20;
+        const ROUNDS: usize = 32;
+
+        let path = std::env::temp_dir().join("hath-bench-hit");
+        std::fs::write(&path, vec![0x5a; SIZE]).unwrap();
+
+        // the previous hit path, 8 KiB reads through tokio file copied into each frame
+        measure("tokio::fs::File, 8 KiB copied", async {
+            let mut total = 0;
+            let mut buf = vec![0; 8192];
+            for _ in 0..ROUNDS {
+                let mut file = File::open(&path).await.unwrap();
+                loop {
+                    let n = file.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    total += black_box(Bytes::copy_from_slice(&buf[..n])).len();
+                }
+            }
+            total
+        })
+        .await;
+
+        let pool = IoPool::new(4);
+        measure("I/O pool, 256 KiB read ahead", async {
+            let mut total = 0;
+            for _ in 0..ROUNDS {
+                let file = Arc::new(std::fs::File::open(&path).unwrap());
+                let mut stream = CacheStream::hit(&pool, file, 0..SIZE as u64, None, None);
+                while let Some(frame) = stream.frame().await {
+                    total += black_box(frame.ok().unwrap().into_data().ok().unwrap()).len();
+                }
+            }
+            total
+        })
+        .await;
+
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,168 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_body_util::BodyExt;
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::Instant;
+
+use crate::AppContext;
+
+use super::{CacheFile, CacheStream};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A file to be fetched on warm-up, along with parameters of `srfetch`.
+pub struct WarmupEntry {
+    pub file: CacheFile,
+    pub fileindex: String,
+    pub xres: String,
+}
+
+/// Parse list of files to warm up cache, each line is `<file id> <fileindex> <xres>`.
+///
+/// Empty lines and lines starting with `#` are skipped, and so are invalid ones with a warning.
+pub fn parse_list(data: &str) -> Vec<WarmupEntry> {
+    let mut entries = Vec::new();
+    for (n, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut iter = line.split_whitespace();
+        match (iter.next().map(CacheFile::try_from), iter.next(), iter.next(), iter.next()) {
+            (Some(Ok(file)), Some(fileindex), Some(xres), None) => entries.push(WarmupEntry {
+                file,
+                fileindex: fileindex.to_owned(),
+                xres: xres.to_owned(),
+            }),
+            _ => log::warn!("invalid warm-up entry at line {}: {}", n + 1, line),
+        }
+    }
+    entries
+}
+
+#[derive(Default)]
+struct Progress {
+    fetched: usize,
+    failed: usize,
+    bytes: u64,
+}
+
+impl AppContext {
+    /// Fetch files in our static ranges which are not cached yet, through the same path as cache misses.
+    ///
+    /// At most `concurrency` files are fetched at once, and files are skipped once they would exceed `budget` bytes.
+    pub async fn warm_up(self: &Arc<Self>, entries: Vec<WarmupEntry>, concurrency: usize, budget: Option<u64>) {
+        let listed = entries.len();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .filter(|e| self.in_static_range(e.file.static_range()))
+            .filter(|e| !self.cache_manager.lock().unwrap().contains(&e.file))
+            .collect();
+        log::info!("warm-up: {} of {} files are in static ranges and not cached", entries.len(), listed);
+        let total = entries.len();
+
+        let (mut scheduled, mut skipped) = (0, 0);
+        let mut progress = Progress::default();
+        let mut reported = Instant::now();
+        let mut tasks = JoinSet::new();
+        for entry in entries {
+            if budget.is_some_and(|b| scheduled + entry.file.info.size > b) {
+                skipped += 1;
+                continue;
+            }
+            scheduled += entry.file.info.size;
+
+            while tasks.len() >= concurrency.max(1) {
+                progress.record(tasks.join_next().await.unwrap());
+                if reported.elapsed() >= REPORT_INTERVAL {
+                    progress.report(total);
+                    reported = Instant::now();
+                }
+            }
+            let ctx = self.clone();
+            tasks.spawn(async move { ctx.warm_up_file(&entry).await.then_some(entry.file.info.size) });
+        }
+        while let Some(result) = tasks.join_next().await {
+            progress.record(result);
+        }
+
+        progress.report(total);
+        if skipped > 0 {
+            log::info!("warm-up: {} files skipped for exceeding budget", skipped);
+        }
+    }
+
+    /// # Return
+    /// `true` if the file is fetched and stored.
+    async fn warm_up_file(self: &Arc<Self>, entry: &WarmupEntry) -> bool {
+        let file = &entry.file;
+        let extra = (entry.fileindex.as_str(), entry.xres.as_str());
+        let stream = match CacheStream::new(self, file, extra, 0..file.info.size).await {
+            Ok(Some(stream)) => stream,
+            Ok(None) => return false,
+            Err(e) => {
+                log::error!("warm-up {}: {}", file.filename(false), e);
+                return false;
+            }
+        };
+
+        let fetch = match &stream {
+            CacheStream::Miss { fetch, .. } => Some(fetch.clone()),
+            _ => None,
+        };
+        // drain the stream, the file is stored by the fetch behind it
+        let mut stream = std::pin::pin!(stream);
+        while let Some(frame) = stream.frame().await {
+            if let Err(e) = frame {
+                log::error!("warm-up {}: {}", file.filename(false), e);
+                return false;
+            }
+        }
+        if let Some(fetch) = fetch {
+            fetch.closed().await;
+        }
+        self.cache_manager.lock().unwrap().contains(file)
+    }
+}
+
+impl Progress {
+    fn record(&mut self, result: Result<Option<u64>, JoinError>) {
+        match result {
+            Ok(Some(size)) => {
+                self.fetched += 1;
+                self.bytes += size;
+            }
+            Ok(None) => self.failed += 1,
+            Err(e) => {
+                log::error!("warm-up task: {}", e);
+                self.failed += 1;
+            }
+        }
+    }
+
+    fn report(&self, total: usize) {
+        log::info!(
+            "warm-up: {} of {} files fetched, {} bytes, {} failed",
+            self.fetched,
+            total,
+            self.bytes,
+            self.failed
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let hash = "0123456789abcdef0123456789abcdef01234567";
+        let data = format!("# comment\n\n{hash}-100-10-10-jpg 123 780\n{hash}-100-10-10-jpg 123\nbad 1 2\n");
+        let entries = parse_list(&data);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].file.filename(true), format!("{hash}-100-10-10-jpg"));
+        assert_eq!((entries[0].fileindex.as_str(), entries[0].xres.as_str()), ("123", "780"));
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::AppContext;
+use crate::utils::hex_to_u8;
+use crate::utils::inotify::{Event, Inotify};
+
+use super::file::{CacheFile, TEMP_SUFFIX};
+use super::manager::dir_iter;
+
+/// Events of directories above shard directories
+const DIR_MASK: u32 = libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_ONLYDIR;
+/// Events of shard directories
+const SHARD_MASK: u32 =
+    libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_ONLYDIR;
+
+/// Level of watched directory in cache root.
+#[derive(Clone, Copy)]
+enum Level {
+    Root,
+    /// `xx` directory, with the first byte of static range
+    Parent(u8),
+    /// `xx/yy` directory, with its static range
+    Shard(u16),
+}
+
+impl Level {
+    fn child(self, name: &OsStr) -> Option<Level> {
+        let b = name.as_encoded_bytes();
+        if b.len() != 2 {
+            return None;
+        }
+        let n = hex_to_u8(b[0], b[1])?;
+        match self {
+            Level::Root => Some(Level::Parent(n)),
+            Level::Parent(parent) => Some(Level::Shard(u16::from_be_bytes([parent, n]))),
+            Level::Shard(_) => None,
+        }
+    }
+}
+
+struct Watch {
+    root: usize,
+    dir: PathBuf,
+    level: Level,
+}
+
+impl AppContext {
+    /// Keep index in sync with files added or removed by others in cache directories.
+    pub async fn watch_cache_dirs(&self) {
+        if !self.watch_cache_dir {
+            return;
+        }
+        let inotify = match Inotify::new() {
+            Ok(inotify) => Arc::new(inotify),
+            Err(e) => return log::error!("watch cache directory: {}", e),
+        };
+
+        let roots = self.cache_manager.lock().unwrap().roots();
+        let watcher = inotify.clone();
+        let watched = tokio::task::spawn_blocking(move || {
+            let mut watched = Vec::new();
+            for (root, dir) in roots {
+                let watch = Watch { root, dir: dir.clone(), level: Level::Root };
+                if let Err(e) = watch_dir(&watcher, watch, &mut watched, None) {
+                    log_watch_error(&dir, &e);
+                }
+            }
+            watched
+        });
+        let mut watches: HashMap<_, _> = watched.await.unwrap().into_iter().collect();
+        log::info!("watching {} cache directories", watches.len());
+
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let events = match inotify.read(&mut buf).await {
+                Ok(events) => events,
+                Err(e) => return log::error!("watch cache directory: {}", e),
+            };
+            for event in events {
+                self.handle_event(&inotify, &mut watches, event).await;
+            }
+        }
+    }
+
+    async fn handle_event(&self, inotify: &Arc<Inotify>, watches: &mut HashMap<i32, Watch>, event: Event) {
+        if event.mask & libc::IN_Q_OVERFLOW != 0 {
+            log::warn!("too many changes in cache directories, index may be out of sync until restart");
+            return;
+        }
+        if event.mask & libc::IN_IGNORED != 0 {
+            // directory is removed
+            watches.remove(&event.wd);
+            return;
+        }
+        let (Some(watch), Some(name)) = (watches.get(&event.wd), &event.name) else { return };
+        let path = watch.dir.join(name);
+        let root = watch.root;
+
+        let level = match watch.level {
+            Level::Shard(shard) => {
+                let name = name.to_str().filter(|n| !n.ends_with(TEMP_SUFFIX));
+                let Some(file) = name.and_then(CacheFile::from_filename) else { return };
+                if file.static_range() != shard {
+                    return;
+                }
+
+                // check the file itself, since events of the same file may come in quick succession
+                let meta = tokio::fs::metadata(&path).await;
+                let mut manager = self.cache_manager.lock().unwrap();
+                match meta {
+                    Ok(meta) if meta.is_file() && meta.len() == file.info.size => manager.found(root, file),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => manager.lost(root, &file),
+                    _ => {}
+                }
+                return;
+            }
+            level => level,
+        };
+
+        // new directory, which may be moved in with files
+        let Some(level) = level.child(name).filter(|_| event.mask & libc::IN_ISDIR != 0) else { return };
+        let inotify = inotify.clone();
+        let watch = Watch { root, dir: path.clone(), level };
+        let (watched, found, result) = tokio::task::spawn_blocking(move || {
+            let (mut watched, mut found) = (Vec::new(), Vec::new());
+            let result = watch_dir(&inotify, watch, &mut watched, Some(&mut found));
+            (watched, found, result)
+        })
+        .await
+        .unwrap();
+
+        if let Err(e) = result {
+            log_watch_error(&path, &e);
+        }
+        watches.extend(watched);
+        let mut manager = self.cache_manager.lock().unwrap();
+        for file in found {
+            manager.found(root, file);
+        }
+    }
+}
+
+/// Watch `dir` and directories below it.
+///
+/// Files already in shard directories are collected into `found` if given, since they're added before watched.
+fn watch_dir(
+    inotify: &Inotify,
+    watch: Watch,
+    watched: &mut Vec<(i32, Watch)>,
+    mut found: Option<&mut Vec<CacheFile>>,
+) -> io::Result<()> {
+    let mask = match watch.level {
+        Level::Shard(_) => SHARD_MASK,
+        Level::Root | Level::Parent(_) => DIR_MASK,
+    };
+    let wd = inotify.add_watch(&watch.dir, mask)?;
+    let (root, dir, level) = (watch.root, watch.dir.clone(), watch.level);
+    watched.push((wd, watch));
+
+    match level {
+        Level::Shard(shard) => {
+            if let Some(found) = found {
+                found.extend(shard_files(&dir, shard)?);
+            }
+        }
+        Level::Root | Level::Parent(_) => {
+            for entry in dir_iter(&dir)? {
+                let Some(level) = level.child(&entry.file_name()) else { continue };
+                let watch = Watch { root, dir: entry.path(), level };
+                watch_dir(inotify, watch, watched, found.as_deref_mut())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Complete files in shard directory, temporary files are left as is since they may be being fetched.
+fn shard_files(dir: &Path, shard: u16) -> io::Result<Vec<CacheFile>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_str().filter(|n| !n.ends_with(TEMP_SUFFIX));
+        let Some(file) = name.and_then(CacheFile::from_filename) else { continue };
+        let len = entry.metadata().ok().filter(|m| m.is_file()).map(|m| m.len());
+        if file.static_range() == shard && len == Some(file.info.size) {
+            files.push(file);
+        }
+    }
+    Ok(files)
+}
+
+fn log_watch_error(dir: &Path, e: &io::Error) {
+    log::error!("unable to watch cache directory {}: {}", dir.display(), e);
+    if e.kind() == io::ErrorKind::StorageFull {
+        log::error!("inotify watches are used up, consider raising fs.inotify.max_user_watches");
+    }
+}
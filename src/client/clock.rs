@@ -0,0 +1,60 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::utils::unix_time;
+use crate::{AppContext, Result};
+
+use super::rpc::ServerStat;
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(3600);
+/// Offset in seconds above which local clock is considered drifting
+const SKEW_WARNING: u64 = 60;
+
+/// Offset from local time to server time, measured at the midpoint of the request.
+fn measure(before: u64, after: u64, server_time: u64) -> i64 {
+    server_time as i64 - (before + after).div_ceil(2) as i64
+}
+
+impl AppContext {
+    /// Current unix time of server, which signs outgoing requests and validates incoming ones.
+    pub fn server_time(&self) -> u64 {
+        unix_time().saturating_add_signed(self.time_offset.load(Ordering::Relaxed))
+    }
+
+    /// Measure offset of local clock from server time.
+    pub async fn sync_time(&self) -> Result<()> {
+        // not retried, since backoff would be counted into round trip
+        let before = unix_time();
+        let ServerStat { server_time } = self.rpc_request_once("server_stat", "").await?;
+        let offset = measure(before, unix_time(), server_time);
+
+        let last = self.time_offset.swap(offset, Ordering::Relaxed);
+        if offset.unsigned_abs() >= SKEW_WARNING {
+            log::warn!("local clock is off by {}s from server time, consider syncing system clock", offset);
+        } else if last != offset {
+            log::debug!("time offset from server: {}s", offset);
+        }
+        Ok(())
+    }
+
+    pub async fn sync_time_periodically(&self) {
+        loop {
+            tokio::time::sleep(SYNC_INTERVAL).await;
+            if let Err(e) = self.sync_time().await {
+                log::error!("sync server time: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn offset() {
+        assert_eq!(measure(100, 102, 101), 0);
+        assert_eq!(measure(101, 102, 1000), 898);
+        assert_eq!(measure(1000, 1000, 880), -120);
+    }
+}
@@ -162,10 +162,10 @@ impl Write for AltLimitedStream {
 impl Connection for AltLimitedStream {
     fn connected(&self) -> Connected {
         let mut connected = Connected::new();
-        if let AltLimitedStream::Tls(stream) = self {
-            if stream.ssl().selected_alpn_protocol() == Some(b"h2") {
-                connected = connected.negotiated_h2()
-            }
+        if let AltLimitedStream::Tls(stream) = self
+            && stream.ssl().selected_alpn_protocol() == Some(b"h2")
+        {
+            connected = connected.negotiated_h2()
         }
         connected
     }
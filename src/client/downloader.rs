@@ -130,12 +130,12 @@ impl GalleryFile {
             match f {
                 Ok(f) => {
                     // if it's data frame, get the data
-                    if let Ok(b) = f.into_data() {
-                        if let Err(e) = file.write_all(&b).await {
-                            log::error!("write cache file: {}", e);
-                            let _ = file.set_len(0).await;
-                            break;
-                        }
+                    if let Ok(b) = f.into_data()
+                        && let Err(e) = file.write_all(&b).await
+                    {
+                        log::error!("write cache file: {}", e);
+                        let _ = file.set_len(0).await;
+                        break;
                     }
                 }
                 Err(_) => {
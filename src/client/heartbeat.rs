@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::utils::{unix_time, write_atomic};
+use crate::{AppContext, Error};
+
+use super::rpc::RpcStatus;
+
+/// File name of heartbeat state in data directory, which is rewritten after every heartbeat for monitoring
+const HEARTBEAT_FILE: &str = "heartbeat.json";
+
+const INTERVAL: Duration = Duration::from_secs(100);
+/// Consecutive failed heartbeats before logging in and starting again
+const MAX_FAILURES: u32 = 3;
+
+#[derive(Serialize, Default, Clone)]
+pub struct HeartbeatState {
+    /// Unix time of the last successful heartbeat
+    pub last_success: Option<u64>,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// Times of logging in and starting again
+    pub recoveries: u32,
+}
+
+impl AppContext {
+    /// Send heartbeat periodically, and log in and start again once it keeps failing or the key is expired.
+    pub async fn heartbeat(&self) {
+        loop {
+            let result = self.alive().await;
+            let recover = {
+                let mut state = self.heartbeat.lock().unwrap();
+                match &result {
+                    Ok(()) => {
+                        state.last_success = Some(unix_time());
+                        state.consecutive_failures = 0;
+                        state.last_error = None;
+                    }
+                    Err(e) => {
+                        state.consecutive_failures += 1;
+                        state.last_error = Some(e.to_string());
+                        log::warn!("heartbeat failed {} times in a row: {}", state.consecutive_failures, e);
+                    }
+                }
+                let expired = matches!(result, Err(Error::Rpc(RpcStatus::KeyExpired, _)));
+                expired || state.consecutive_failures >= MAX_FAILURES
+            };
+
+            if recover {
+                log::warn!("login and start again");
+                match self.restart().await {
+                    Ok(()) => {
+                        let mut state = self.heartbeat.lock().unwrap();
+                        state.recoveries += 1;
+                        state.consecutive_failures = 0;
+                        state.last_success = Some(unix_time());
+                        state.last_error = None;
+                    }
+                    Err(e) => log::error!("login and start again: {}", e),
+                }
+            }
+
+            self.save_heartbeat().await;
+            tokio::time::sleep(INTERVAL).await;
+        }
+    }
+
+    async fn restart(&self) -> crate::Result<()> {
+        self.login().await?;
+        self.notify_start().await
+    }
+
+    async fn save_heartbeat(&self) {
+        let state = self.heartbeat.lock().unwrap().clone();
+        let data = serde_json::to_vec(&state).unwrap();
+        // it's read by monitoring at any time
+        if let Err(e) = write_atomic(&self.data_dir.join(HEARTBEAT_FILE), &data).await {
+            log::error!("save heartbeat state: {}", e);
+        }
+    }
+}
@@ -6,29 +6,39 @@ use hyper::body::{Body, Incoming};
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 
+use crate::cache::CacheFile;
 use crate::{CLIENT_VER, AppContext, Result, Error};
 use crate::utils::sha1_digest;
 
+mod clock;
 mod connector;
 pub mod downloader;
+mod heartbeat;
+pub mod rpc;
 
 pub(crate) use connector::HttpClient;
+pub use heartbeat::HeartbeatState;
 use downloader::DownloadMeta;
+use rpc::{FetchUrls, RpcResponse, Settings};
 
 impl AppContext {
     fn get_uri(&self, act: &str, add: &str) -> Uri {
-        // parameters
-        let id = self.id.to_string();
-        let time = crate::unix_time().to_string();
-
-        // key
-        let key = sha1_digest(&["hentai@home", act, add, &id, &time, &self.key]);
-
-        // uri
-        let path = format!(
-            "/15/rpc?clientbuild={}&act={}&add={}&cid={}&acttime={}&actkey={}",
-            CLIENT_VER, act, add, id, time, key
-        );
+        let path = if act == "server_stat" {
+            // unsigned, since server time is not known yet
+            format!("/15/rpc?clientbuild={}&act={}", CLIENT_VER, act)
+        } else {
+            // parameters
+            let id = self.id.to_string();
+            let time = self.server_time().to_string();
+
+            // key
+            let key = sha1_digest(&["hentai@home", act, add, &id, &time, &self.key]);
+
+            format!(
+                "/15/rpc?clientbuild={}&act={}&add={}&cid={}&acttime={}&actkey={}",
+                CLIENT_VER, act, add, id, time, key
+            )
+        };
 
         Uri::builder()
             .scheme(Scheme::HTTP)
@@ -38,7 +48,25 @@ impl AppContext {
             .unwrap()
     }
 
-    async fn rpc_request(&self, act: &str, add: &str) -> Result<Vec<String>> {
+    /// Send RPC request, transient failures are retried with backoff within budget of the action.
+    async fn rpc_request<T: RpcResponse>(&self, act: &str, add: &str) -> Result<T> {
+        let budget = rpc::retry_budget(act);
+        let mut attempt = 0;
+        loop {
+            match self.rpc_request_once(act, add).await {
+                Err(e) if attempt < budget && rpc::is_transient(&e) => {
+                    let delay = rpc::backoff(attempt);
+                    log::warn!("client request {} failed: {}, retry in {:.1}s", act, e, delay.as_secs_f64());
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                ret => return ret,
+            }
+        }
+    }
+
+    async fn rpc_request_once<T: RpcResponse>(&self, act: &str, add: &str) -> Result<T> {
+        // acttime is taken on each attempt
         let uri = self.get_uri(act, add);
         log::info!("client reqeust: {}", act);
 
@@ -55,19 +83,11 @@ impl AppContext {
         let body = response.into_body().collect().await?.to_bytes();
         let body = std::str::from_utf8(&body)?;
 
-        let mut iter = body.split('\n');
-        let first = iter.next();
-        match first {
-            Some("OK") => {}
-            Some(err) => {
-                log::error!("request error: {err}");
-                return Err(Error::BadResponse);
-            }
-            None => return Err(Error::BadResponse),
+        let ret = rpc::parse_body(body);
+        if let Err(Error::Rpc(status, message)) = &ret {
+            log::error!("request error: {}: {} {}", act, status, message);
         }
-
-        let data = iter.map(|x| x.to_string()).collect();
-        Ok(data)
+        ret
     }
 
     async fn download(&self, uri: Uri, path: PathBuf) -> Result<File> {
@@ -89,28 +109,29 @@ impl AppContext {
     }
 
     pub async fn login(&self) -> Result<()> {
-        let data = self.rpc_request("client_login", "").await?;
-        self.update(data)
+        // login is rejected if acttime is too far from server time
+        if let Err(e) = self.sync_time().await {
+            log::error!("sync server time: {}", e);
+        }
+        let settings = self.rpc_request("client_login", "").await?;
+        self.update(settings)
     }
 
     pub async fn notify_start(&self) -> Result<()> {
-        self.rpc_request("client_start", "").await?;
-        Ok(())
+        self.rpc_request("client_start", "").await
     }
 
     pub async fn alive(&self) -> Result<()> {
-        self.rpc_request("still_alive", "").await?;
-        Ok(())
+        self.rpc_request("still_alive", "").await
     }
 
     pub async fn update_settings(&self) -> Result<()> {
-        let data = self.rpc_request("client_settings", "").await?;
-        self.update(data)
+        let settings: Settings = self.rpc_request("client_settings", "").await?;
+        self.update(settings)
     }
 
     pub async fn shutdown(&self) -> Result<()> {
-        self.rpc_request("client_stop", "").await?;
-        Ok(())
+        self.rpc_request("client_stop", "").await
     }
 
     pub async fn download_cert(&self) -> Result<File> {
@@ -123,9 +144,8 @@ impl AppContext {
 
     pub async fn static_range_fetch(&self, index: &str, xres: &str, file_id: &str) -> Result<Option<Incoming>> {
         let add = format!("{};{};{}", index, xres, file_id);
-        let res = self.rpc_request("srfetch", &add).await?;
-        let iter = res.into_iter().filter_map(|s| Uri::try_from(s.to_string()).ok());
-        for uri in iter {
+        let FetchUrls(urls) = self.rpc_request("srfetch", &add).await?;
+        for uri in urls {
             if let Ok(res) = self.client.get(uri).await {
                 return Ok(Some(res.into_body()));
             }
@@ -133,6 +153,11 @@ impl AppContext {
         Ok(None)
     }
 
+    /// Get files blacklisted in last `delta` seconds.
+    pub async fn get_blacklist(&self, delta: u64) -> Result<Vec<CacheFile>> {
+        self.rpc_request("get_blacklist", &delta.to_string()).await
+    }
+
     /// # Argument
     ///
     /// - downloaded: tell server that gallery is completely downloaded
@@ -144,7 +169,7 @@ impl AppContext {
         };
 
         // parameters
-        let time = crate::unix_time().to_string();
+        let time = self.server_time().to_string();
         let id = self.id.to_string();
 
         // key
@@ -179,9 +204,8 @@ impl AppContext {
         retry: u32,
     ) -> Result<Option<Incoming>> {
         let add = format!("{gid};{page};{file_index};{xres};{retry}");
-        let res = self.rpc_request("dlfetch", &add).await?;
-        let iter = res.into_iter().filter_map(|s| Uri::try_from(s.to_string()).ok());
-        for uri in iter {
+        let FetchUrls(urls) = self.rpc_request("dlfetch", &add).await?;
+        for uri in urls {
             if let Ok(res) = self.client.get(uri).await {
                 return Ok(Some(res.into_body()));
             }
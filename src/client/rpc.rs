@@ -0,0 +1,189 @@
+use std::fmt;
+use std::time::Duration;
+
+use http::Uri;
+
+use crate::cache::CacheFile;
+use crate::{Error, Result};
+
+/// Status of RPC response, which is the first line of its body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RpcStatus {
+    Ok,
+    /// `acttime` is too far from server time, or the key is invalid
+    KeyExpired,
+    TemporarilyUnavailable,
+    InvalidRequest,
+    /// Server is unable to connect to our port
+    FailConnectTest,
+    /// Client is restarted too often
+    FailStartupFlood,
+    /// Another client is logged in with the same ID
+    FailOtherClientConnected,
+    Other(String),
+}
+
+impl From<&str> for RpcStatus {
+    fn from(value: &str) -> Self {
+        match value {
+            "OK" => RpcStatus::Ok,
+            "KEY_EXPIRED" => RpcStatus::KeyExpired,
+            "TEMPORARILY_UNAVAILABLE" => RpcStatus::TemporarilyUnavailable,
+            "INVALID_REQUEST" => RpcStatus::InvalidRequest,
+            "FAIL_CONNECT_TEST" => RpcStatus::FailConnectTest,
+            "FAIL_STARTUP_FLOOD" => RpcStatus::FailStartupFlood,
+            "FAIL_OTHER_CLIENT_CONNECTED" => RpcStatus::FailOtherClientConnected,
+            s => RpcStatus::Other(s.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for RpcStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            RpcStatus::Ok => "OK",
+            RpcStatus::KeyExpired => "KEY_EXPIRED",
+            RpcStatus::TemporarilyUnavailable => "TEMPORARILY_UNAVAILABLE",
+            RpcStatus::InvalidRequest => "INVALID_REQUEST",
+            RpcStatus::FailConnectTest => "FAIL_CONNECT_TEST",
+            RpcStatus::FailStartupFlood => "FAIL_STARTUP_FLOOD",
+            RpcStatus::FailOtherClientConnected => "FAIL_OTHER_CLIENT_CONNECTED",
+            RpcStatus::Other(s) => s,
+        };
+        f.write_str(s)
+    }
+}
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Number of retries of each action on transient failures.
+pub fn retry_budget(act: &str) -> u32 {
+    match act {
+        // node can't serve without them
+        "client_login" | "client_start" => 5,
+        // client is waiting for the file
+        "srfetch" | "dlfetch" => 1,
+        // heartbeat is sent again later anyway
+        "still_alive" | "client_stop" => 2,
+        _ => 3,
+    }
+}
+
+/// Failures which may succeed on retry.
+pub fn is_transient(e: &Error) -> bool {
+    matches!(e, Error::IO(_) | Error::Hyper(_) | Error::BadResponse | Error::Rpc(RpcStatus::TemporarilyUnavailable, _))
+}
+
+/// Delay before retry, which is doubled on each attempt with random jitter of up to half of it.
+pub fn backoff(attempt: u32) -> Duration {
+    let delay = BACKOFF_BASE.saturating_mul(1 << attempt.min(16)).min(BACKOFF_MAX);
+    delay / 2 + (delay / 2).mul_f64(rand::random::<f64>())
+}
+
+/// Response of an RPC action, parsed from lines after the status line.
+pub trait RpcResponse: Sized {
+    fn parse(lines: Vec<&str>) -> Result<Self>;
+}
+
+impl RpcResponse for () {
+    fn parse(_: Vec<&str>) -> Result<Self> {
+        Ok(())
+    }
+}
+
+/// Settings in response of `client_login` and `client_settings`.
+pub struct Settings(pub Vec<(String, String)>);
+
+impl RpcResponse for Settings {
+    fn parse(lines: Vec<&str>) -> Result<Self> {
+        let iter = lines.into_iter().filter_map(|s| s.split_once('='));
+        Ok(Settings(iter.map(|(k, v)| (k.to_owned(), v.to_owned())).collect()))
+    }
+}
+
+/// URLs to fetch a file from, in response of `srfetch` and `dlfetch`.
+pub struct FetchUrls(pub Vec<Uri>);
+
+impl RpcResponse for FetchUrls {
+    fn parse(lines: Vec<&str>) -> Result<Self> {
+        Ok(FetchUrls(lines.into_iter().filter_map(|s| Uri::try_from(s).ok()).collect()))
+    }
+}
+
+/// Response of `server_stat`, only server time is used.
+pub struct ServerStat {
+    pub server_time: u64,
+}
+
+impl RpcResponse for ServerStat {
+    fn parse(lines: Vec<&str>) -> Result<Self> {
+        let value = lines.into_iter().find_map(|s| s.trim().strip_prefix("server_time="));
+        let server_time = value.ok_or(Error::BadResponse)?.parse()?;
+        Ok(ServerStat { server_time })
+    }
+}
+
+/// Blacklisted files in response of `get_blacklist`.
+impl RpcResponse for Vec<CacheFile> {
+    fn parse(lines: Vec<&str>) -> Result<Self> {
+        Ok(lines.into_iter().filter_map(|s| CacheFile::try_from(s.trim()).ok()).collect())
+    }
+}
+
+/// Parse body of RPC response.
+///
+/// # Return
+/// [`Error::Rpc`] with the message of server if status is not `OK`.
+pub fn parse_body<T: RpcResponse>(body: &str) -> Result<T> {
+    let mut iter = body.split('\n');
+    let status = match iter.next() {
+        Some(s) if !s.trim().is_empty() => RpcStatus::from(s.trim()),
+        _ => return Err(Error::BadResponse),
+    };
+    if status != RpcStatus::Ok {
+        let message = iter.map(str::trim).filter(|s| !s.is_empty()).collect::<Vec<_>>().join("; ");
+        return Err(Error::Rpc(status, message));
+    }
+    T::parse(iter.collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let Settings(settings) = parse_body("OK\nstatic_ranges=0001;0002\nuse_less_memory=false\n").ok().unwrap();
+        assert_eq!(settings[0], ("static_ranges".to_owned(), "0001;0002".to_owned()));
+        assert_eq!(settings.len(), 2);
+
+        let FetchUrls(urls) = parse_body("OK\nhttp://127.0.0.1/a\n").ok().unwrap();
+        assert_eq!(urls.len(), 1);
+
+        match parse_body::<()>("KEY_EXPIRED\nkey expired at 100\n") {
+            Err(Error::Rpc(RpcStatus::KeyExpired, message)) => assert_eq!(message, "key expired at 100"),
+            _ => panic!("unexpected result"),
+        }
+        assert!(matches!(parse_body::<()>("SOMETHING_NEW"), Err(Error::Rpc(RpcStatus::Other(s), _)) if s == "SOMETHING_NEW"));
+        assert!(matches!(parse_body::<()>(""), Err(Error::BadResponse)));
+
+        let stat: ServerStat = parse_body("OK\nmin_client_build=169\nserver_time=1700000000\n").ok().unwrap();
+        assert_eq!(stat.server_time, 1700000000);
+        assert!(matches!(parse_body::<ServerStat>("OK\n"), Err(Error::BadResponse)));
+    }
+
+    #[test]
+    fn retry() {
+        assert!(is_transient(&Error::Rpc(RpcStatus::TemporarilyUnavailable, String::new())));
+        assert!(!is_transient(&Error::Rpc(RpcStatus::KeyExpired, String::new())));
+
+        let secs = |a: f64, b: f64| Duration::from_secs_f64(a)..=Duration::from_secs_f64(b);
+        assert!(secs(0.5, 1.0).contains(&backoff(0)));
+        assert!(secs(1.0, 2.0).contains(&backoff(1)));
+        assert!(secs(4.0, 8.0).contains(&backoff(3)));
+        // capped at 60s
+        assert!(secs(30.0, 60.0).contains(&backoff(6)));
+        assert!(secs(30.0, 60.0).contains(&backoff(100)));
+    }
+}
@@ -1,12 +1,24 @@
 use std::collections::HashSet;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicI64;
 use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 
-use crate::cache::CacheManager;
-use crate::client::HttpClient;
-use crate::utils::Limiter;
+use tokio::sync::Notify;
+
+use crate::cache::{self, CacheFile, CacheManager, InFlight, SNAPSHOT_FILE};
+use crate::client::{HeartbeatState, HttpClient};
+use crate::client::rpc::Settings;
+use crate::utils::{self, IoPool, Limiter};
 use crate::{Config, Error};
 
+/// Number of threads reading cached files
+const IO_THREADS: usize = 8;
+
+const DEFAULT_DISK_RESERVE: u64 = 1 << 30;
+const DEFAULT_DISK_FLOOR: u64 = 256 << 20;
+
 #[derive(Default)]
 pub struct MutContext {
     pub static_range: HashSet<u16>,
@@ -16,17 +28,29 @@ pub struct AppContext {
     // Immutable Context
     pub id: u32,
     pub key: String,
-    pub cache_dir: PathBuf,
     pub data_dir: PathBuf,
+    pub quarantine_dir: Option<PathBuf>,
+    pub scrub_speed: Option<u32>,
+    pub watch_cache_dir: bool,
 
     /// Local config override
     speedlimit: Option<u32>,
     max_cache_size: Option<u64>,
+    static_range_grace: Option<Duration>,
+    disk_reserve: u64,
+    disk_floor: u64,
 
     // Mutable Context
     pub limiter: Limiter,
     pub mut_context: RwLock<MutContext>,
+    static_range_changed: Notify,
     pub cache_manager: Mutex<CacheManager>,
+    pub in_flight: InFlight,
+    pub io_pool: IoPool,
+    /// Saved in data directory as well
+    pub heartbeat: Mutex<HeartbeatState>,
+    /// Seconds of server time ahead of local time
+    pub time_offset: AtomicI64,
 
     pub client: HttpClient,
 }
@@ -40,23 +64,37 @@ impl AppContext {
         let client = HttpClient::new(limiter.clone())?;
         let mut_context = RwLock::new(MutContext::default());
 
-        let mut cache_manager = CacheManager::new();
+        let mut cache_manager = CacheManager::new(config.eviction_policy, config.cache_dirs(), config.placement);
+        // index is built in background, see `AppContext::build_index`
         if let Some(size) = config.max_cache_size {
             cache_manager.set_max_size(size);
         }
-        cache_manager.build(&config.cache_dir)?;
+        if let Some(size) = config.memory_cache_size {
+            cache_manager.set_memory_size(size);
+        }
+        cache_manager.set_admission(config.admission_policy);
 
         Ok(AppContext {
             id: config.id,
             key: config.key,
-            cache_dir: config.cache_dir,
             data_dir: config.data_dir,
+            quarantine_dir: config.quarantine_dir,
+            scrub_speed: config.scrub_speed,
+            watch_cache_dir: config.watch_cache_dir,
             speedlimit: config.speedlimit,
             max_cache_size: config.max_cache_size,
+            static_range_grace: config.static_range_grace.map(Duration::from_secs),
+            disk_reserve: config.disk_reserve.unwrap_or(DEFAULT_DISK_RESERVE),
+            disk_floor: config.disk_floor.unwrap_or(DEFAULT_DISK_FLOOR),
             limiter,
             mut_context,
+            static_range_changed: Notify::new(),
             cache_manager: Mutex::new(cache_manager),
+            in_flight: InFlight::default(),
+            io_pool: IoPool::new(IO_THREADS),
             client,
+            heartbeat: Mutex::new(HeartbeatState::default()),
+            time_offset: AtomicI64::new(0),
         })
     }
 
@@ -66,20 +104,22 @@ impl AppContext {
         guard.static_range.contains(&range)
     }
 
-    pub fn update(&self, vec: Vec<String>) -> Result<(), Error> {
+    pub fn update(&self, settings: Settings) -> Result<(), Error> {
         let mut speedlimit = 0f64; // unit: KiB/s
 
         let mut guard = self.mut_context.write().unwrap();
-        let iter = vec.iter().filter_map(|s| s.split_once('='));
-        for (key, val) in iter {
-            match key {
+        for (key, val) in &settings.0 {
+            let val = val.as_str();
+            match key.as_str() {
                 "static_ranges" => {
-                    guard.static_range.clear();
-                    val.trim_end_matches(';').split(';').try_for_each(|x| -> Result<(), std::num::ParseIntError> {
-                        let num = u16::from_str_radix(x, 16)?;
-                        guard.static_range.insert(num);
-                        Ok(())
-                    })?;
+                    // parse before replacing, so that a malformed list would not remove valid ranges
+                    let ranges = val
+                        .trim_end_matches(';')
+                        .split(';')
+                        .map(|x| u16::from_str_radix(x, 16))
+                        .collect::<Result<HashSet<_>, _>>()?;
+                    guard.static_range = ranges;
+                    self.static_range_changed.notify_one();
                 }
                 "disable_bwm" => {
                     if self.speedlimit.is_none() && val == "true" {
@@ -97,7 +137,9 @@ impl AppContext {
                         self.cache_manager.lock().unwrap().set_max_size(size);
                     }
                 }
-                "use_less_memory" => {}
+                "use_less_memory" => {
+                    self.cache_manager.lock().unwrap().set_memory_enabled(val != "true");
+                }
                 "disable_logging" => {}
                 _ => log::debug!("unimplemented setting: {}: {}", key, val),
             }
@@ -109,4 +151,191 @@ impl AppContext {
         }
         Ok(())
     }
+
+    /// Remove cached files out of assigned static ranges whenever ranges are updated.
+    ///
+    /// Files are kept for the configured grace period, in case their ranges are assigned back.
+    pub async fn reconcile_static_ranges(&self) {
+        loop {
+            self.static_range_changed.notified().await;
+            if let Some(grace) = self.static_range_grace {
+                tokio::time::sleep(grace).await;
+            }
+
+            let ranges = self.mut_context.read().unwrap().static_range.clone();
+            let (count, size) = self.cache_manager.lock().unwrap().remove_out_of_range(&ranges);
+            if count != 0 {
+                log::info!("removed {} files out of static ranges, {} bytes freed", count, size);
+            }
+        }
+    }
+
+    /// Check blacklist periodically, and remove blacklisted files from cache.
+    pub async fn check_blacklist(&self) {
+        const INTERVAL: u64 = 43200;
+
+        // node might be offline for a while before started
+        let mut delta = 3 * 86400;
+        loop {
+            match self.get_blacklist(delta).await {
+                Ok(files) => {
+                    let purged = self.purge_files(&files).await;
+                    log::info!("{} files blacklisted, {} purged from cache", files.len(), purged);
+                    delta = INTERVAL;
+                }
+                Err(e) => {
+                    log::error!("get blacklist: {}", e);
+                    delta += INTERVAL;
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(INTERVAL)).await;
+        }
+    }
+
+    /// Remove files from index and disk, no matter if they are indexed.
+    ///
+    /// # Return
+    /// Number of files removed from disk.
+    async fn purge_files(&self, files: &[CacheFile]) -> usize {
+        let roots = self.cache_manager.lock().unwrap().roots();
+        let mut count = 0;
+        for file in files {
+            self.cache_manager.lock().unwrap().remove(file);
+            for (_, dir) in &roots {
+                match tokio::fs::remove_file(file.path(dir)).await {
+                    Ok(()) => count += 1,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => log::error!("unable to remove file: {}: {}", file.filename(false), e),
+                }
+            }
+        }
+        count
+    }
+
+    /// Check free space of cache directories periodically, so that other usage of the disk is respected.
+    pub async fn watch_disk_space(&self) {
+        const INTERVAL: Duration = Duration::from_secs(30);
+
+        loop {
+            let roots = self.cache_manager.lock().unwrap().roots();
+            for (root, dir) in roots {
+                match self.io_pool.run(move || utils::disk_free(&dir)).await {
+                    Ok(free) => {
+                        let mut manager = self.cache_manager.lock().unwrap();
+                        manager.set_free_space(root, free, self.disk_reserve, self.disk_floor);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Unsupported => return,
+                    Err(e) => {
+                        log::error!("check free space: {}", e);
+                        self.cache_manager.lock().unwrap().io_error(root, &e);
+                    }
+                }
+            }
+            tokio::time::sleep(INTERVAL).await;
+        }
+    }
+
+    /// Try writing into cache directories which are not writable or out of service periodically,
+    /// and store files there again once succeeded.
+    ///
+    /// Directories out of service are scanned again, since their files are dropped from index.
+    pub async fn check_writable(&self) {
+        const INTERVAL: Duration = Duration::from_secs(60);
+
+        loop {
+            tokio::time::sleep(INTERVAL).await;
+            let roots = self.cache_manager.lock().unwrap().unwritable_roots();
+            for (root, dir) in roots {
+                match probe_write(&dir).await {
+                    Ok(()) => self.cache_manager.lock().unwrap().set_writable(root),
+                    Err(e) => log::debug!("cache directory {} is still not writable: {}", dir.display(), e),
+                }
+            }
+
+            let roots = self.cache_manager.lock().unwrap().failed_roots();
+            for (root, dir) in roots {
+                if let Err(e) = probe_write(&dir).await {
+                    log::debug!("cache directory {} is still out of service: {}", dir.display(), e);
+                    continue;
+                }
+                let (snapshot, scan_dir, in_flight) = (self.snapshot_path(root), dir.clone(), self.in_flight.clone());
+                let scan = move || cache::scan(&scan_dir, &snapshot, &in_flight);
+                let scanned = tokio::task::spawn_blocking(scan).await.unwrap();
+                match scanned {
+                    Ok(files) => self.cache_manager.lock().unwrap().restore_root(root, files),
+                    Err(e) => log::debug!("cache directory {} is still out of service: {}", dir.display(), e),
+                }
+            }
+        }
+    }
+
+    /// Build cache index in background, cached files are served directly from disk meanwhile.
+    pub async fn build_index(&self) {
+        let roots = self.cache_manager.lock().unwrap().roots();
+        // roots are usually on different disks, so they are scanned in parallel
+        let tasks: Vec<_> = roots
+            .into_iter()
+            .map(|(i, dir)| {
+                let (snapshot, in_flight) = (self.snapshot_path(i), self.in_flight.clone());
+                tokio::task::spawn_blocking(move || cache::scan(&dir, &snapshot, &in_flight))
+            })
+            .collect();
+        let mut scanned = Vec::with_capacity(tasks.len());
+        for task in tasks {
+            scanned.push(task.await.unwrap_or_else(|e| Err(io::Error::other(e))));
+        }
+
+        self.cache_manager.lock().unwrap().load(scanned);
+        // files out of static ranges could be found now
+        self.static_range_changed.notify_one();
+    }
+
+    /// Path of index snapshot of cache root.
+    fn snapshot_path(&self, root: usize) -> PathBuf {
+        match root {
+            0 => self.data_dir.join(SNAPSHOT_FILE),
+            n => self.data_dir.join(format!("{}.{}", SNAPSHOT_FILE, n)),
+        }
+    }
+
+    /// Save index snapshot of cache roots into data directory.
+    pub async fn save_index(&self) -> io::Result<()> {
+        let count = self.cache_manager.lock().unwrap().root_count();
+        for root in 0..count {
+            // any change after this time would mark its shard as out of date
+            let time = SystemTime::now();
+            let Some(data) = self.cache_manager.lock().unwrap().snapshot(root, time) else {
+                log::info!("cache index of root {} is not available, skip saving snapshot", root);
+                continue;
+            };
+
+            utils::write_atomic(&self.snapshot_path(root), &data).await?;
+        }
+        Ok(())
+    }
+
+    /// Remove files still pending removal, which would otherwise be left on disk but not in saved index.
+    pub async fn remove_deferred(&self) {
+        let paths = self.cache_manager.lock().unwrap().take_deferred();
+        for path in paths {
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                log::error!("unable to remove file: {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    pub async fn save_index_periodically(&self) {
+        loop {
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            if let Err(e) = self.save_index().await {
+                log::error!("save index snapshot: {}", e);
+            }
+        }
+    }
+}
+
+async fn probe_write(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(".write_probe");
+    tokio::fs::write(&probe, b"hath").await?;
+    tokio::fs::remove_file(&probe).await
 }
@@ -8,6 +8,8 @@ use axum::body::Body;
 use axum::response::IntoResponse;
 use hyper::StatusCode;
 
+use crate::client::rpc::RpcStatus;
+
 // TODO: shell we sperate client and server error?
 #[derive(Debug)]
 pub enum Error {
@@ -18,6 +20,8 @@ pub enum Error {
     OpenSSL(openssl::error::ErrorStack),
     Ssl(openssl::ssl::Error),
     BadResponse,
+    /// RPC server responded with a status other than `OK`, along with its message
+    Rpc(RpcStatus, String),
     BadRequest,
     NotFound,
 
@@ -25,6 +29,8 @@ pub enum Error {
     InvalidUri,
 
     IncompleteCertFile,
+    /// Fetched file does not match the hash or size in its file ID
+    CorruptedFile,
 
     Infallible,
 }
@@ -48,6 +54,8 @@ impl fmt::Display for Error {
             Hyper(e) => e.fmt(f),
             OpenSSL(e) => e.fmt(f),
             Ssl(e) => e.fmt(f),
+            Rpc(status, message) if message.is_empty() => write!(f, "rpc: {}", status),
+            Rpc(status, message) => write!(f, "rpc: {}: {}", status, message),
             _ => write!(f, "{:?}", self)
         }
     }
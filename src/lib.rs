@@ -14,11 +14,11 @@ mod error;
 mod server;
 mod utils;
 
+use crate::cache::{AdmissionKind, Placement, PolicyKind};
 use crate::context::AppContext;
 use crate::error::Error;
 use crate::server::Server;
 use crate::server::ServerContext;
-use crate::utils::unix_time;
 
 pub type Result<T, E = error::Error> = std::result::Result<T, E>;
 
@@ -37,55 +37,180 @@ pub struct Config {
     pub speedlimit: Option<u32>,
     pub max_cache_size: Option<u64>,
 
-    pub cache_dir: PathBuf,
+    pub cache_dir: CacheDirs,
+    #[serde(default)]
+    pub placement: Placement,
+    /// Bytes to keep free on filesystem of each cache directory
+    pub disk_reserve: Option<u64>,
+    /// Fetched files are not stored when free space is below this
+    pub disk_floor: Option<u64>,
     pub data_dir: PathBuf,
+
+    /// Memory budget in bytes to keep hot files, disabled if not set
+    pub memory_cache_size: Option<u64>,
+
+    #[serde(default)]
+    pub eviction_policy: PolicyKind,
+    /// Policy deciding whether a fetched file is stored, every file is stored by default
+    #[serde(default)]
+    pub admission_policy: AdmissionKind,
+    /// Seconds to keep files whose static range is no longer assigned
+    pub static_range_grace: Option<u64>,
+    /// Speed of re-hashing cached files in KiB/s, scrubber is disabled if not set
+    pub scrub_speed: Option<u32>,
+    /// Directory to keep corrupted files found by scrubber, they are deleted if not set
+    pub quarantine_dir: Option<PathBuf>,
+    /// Keep index in sync with files added or removed by others in cache directories, only supported on Linux
+    #[serde(default)]
+    pub watch_cache_dir: bool,
+}
+
+/// Either a single cache directory, or a list of directories with their own capacity.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub enum CacheDirs {
+    Single(PathBuf),
+    Multiple(Vec<CacheDir>),
+}
+
+#[derive(serde::Deserialize)]
+pub struct CacheDir {
+    pub path: PathBuf,
+    /// Size limit in bytes, which is also limited by the total cache size
+    pub max_size: Option<u64>,
 }
 
 impl Config {
     pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Config> {
         let file = File::open(path)?;
-        serde_json::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        let config: Config = serde_json::from_reader(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if config.cache_dirs().is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no cache directory"));
+        }
+        Ok(config)
+    }
+
+    /// Cache directories and their size limits.
+    pub fn cache_dirs(&self) -> Vec<(PathBuf, Option<u64>)> {
+        match &self.cache_dir {
+            CacheDirs::Single(path) => vec![(path.clone(), None)],
+            CacheDirs::Multiple(dirs) => dirs.iter().map(|d| (d.path.clone(), d.max_size)).collect(),
+        }
     }
 }
 
 pub async fn main(config: Config) -> Result<()> {
-    unsafe { simple_logger::init().unwrap_unchecked() };
-    log::set_max_level(config.log_level);
+    init_logger(config.log_level);
 
     init_openssl()?;
 
     let bind_addr = config.bind;
     // start client & login
-    let ctx = AppContext::from_config(config)?;
+    let ctx = Arc::new(AppContext::from_config(config)?);
+    let index_ctx = ctx.clone();
+    tokio::spawn(async move {
+        index_ctx.build_index().await;
+        // scrubber walks through the index
+        index_ctx.scrub_cache().await
+    });
     log::info!("login to H@H network");
     ctx.login().await?;
 
     // start server
     let file = ctx.download_cert().await?;
 
-    let ctx = Arc::new(ctx);
+    let reconcile_ctx = ctx.clone();
+    tokio::spawn(async move { reconcile_ctx.reconcile_static_ranges().await });
+    let blacklist_ctx = ctx.clone();
+    tokio::spawn(async move { blacklist_ctx.check_blacklist().await });
+    let watchdog_ctx = ctx.clone();
+    tokio::spawn(async move { watchdog_ctx.watch_disk_space().await });
+    let writable_ctx = ctx.clone();
+    tokio::spawn(async move { writable_ctx.check_writable().await });
+    let snapshot_ctx = ctx.clone();
+    tokio::spawn(async move { snapshot_ctx.save_index_periodically().await });
+    let clock_ctx = ctx.clone();
+    tokio::spawn(async move { clock_ctx.sync_time_periodically().await });
+    #[cfg(target_os = "linux")]
+    {
+        let watcher_ctx = ctx.clone();
+        tokio::spawn(async move { watcher_ctx.watch_cache_dirs().await });
+    }
     let server_ctx = ServerContext::new(file, &ctx).await?;
     let server = Server::new(bind_addr, server_ctx).await?;
     tokio::spawn(server.run());
 
+    tokio::spawn(report_cache_stats(ctx.clone()));
+
     // client event loop
     ctx.notify_start().await?;
-    let alive = async {
-        loop {
-            let _ = ctx.alive().await;
-            tokio::time::sleep(Duration::from_secs(100)).await;
-        }
-    };
     tokio::select! {
-        _ = alive => (),
+        _ = ctx.heartbeat() => (),
         _ = tokio::signal::ctrl_c() => ()
     };
 
     log::info!("signal exit, shutting down...");
+    ctx.remove_deferred().await;
+    if let Err(e) = ctx.save_index().await {
+        log::error!("save index snapshot: {}", e);
+    }
     ctx.shutdown().await?;
     Ok(())
 }
 
+/// Pre-populate cache with files listed in `list`, see [`cache::parse_list`] for its format.
+///
+/// Files in our static ranges are fetched with at most `concurrency` at once, within `budget` bytes if given.
+pub async fn warm_up(config: Config, list: &Path, concurrency: usize, budget: Option<u64>) -> Result<()> {
+    init_logger(config.log_level);
+
+    init_openssl()?;
+
+    let entries = cache::parse_list(&tokio::fs::read_to_string(list).await?);
+    // every listed file is wanted, which is not seen by admission policy
+    let config = Config { admission_policy: AdmissionKind::Always, ..config };
+    let ctx = Arc::new(AppContext::from_config(config)?);
+    // size limit of cache may be given by login, which is needed before index is loaded
+    log::info!("login to H@H network");
+    ctx.login().await?;
+    ctx.build_index().await;
+
+    ctx.warm_up(entries, concurrency, budget).await;
+    // fetches shared with a failed warm-up may still be storing files
+    ctx.in_flight.drained().await;
+    ctx.save_index().await?;
+    Ok(())
+}
+
+/// Verify cached files offline, bad files are removed or moved into quarantine directory if `repair` is set.
+///
+/// # Return
+/// `false` if any bad file is found.
+pub async fn check(config: Config, repair: bool) -> Result<bool> {
+    init_logger(config.log_level);
+
+    let mut clean = true;
+    for (dir, _) in config.cache_dirs() {
+        log::info!("checking cache directory: {}", dir.display());
+        let report = cache::check(&dir, config.quarantine_dir.as_deref(), repair).await?;
+        log::info!("{}", report);
+        clean &= report.is_clean();
+    }
+    Ok(clean)
+}
+
+fn init_logger(level: log::LevelFilter) {
+    unsafe { simple_logger::init().unwrap_unchecked() };
+    log::set_max_level(level);
+}
+
+async fn report_cache_stats(ctx: Arc<AppContext>) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+        ctx.cache_manager.lock().unwrap().report();
+    }
+}
+
 /// Load OpenSSL legacy and default providers.
 fn init_openssl() -> Result<(), ErrorStack> {
     use openssl::provider::Provider;
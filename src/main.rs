@@ -1,21 +1,84 @@
 use std::env::args;
+use std::path::Path;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: hath <config>
+       hath check <config> [--repair]
+       hath warmup <config> <list> [--concurrency <n>] [--budget <bytes>]";
+
+/// Files fetched at once on warm-up by default
+const WARMUP_CONCURRENCY: usize = 8;
+
+enum Mode<'a> {
+    Run,
+    Check { repair: bool },
+    Warmup { list: &'a str, concurrency: usize, budget: Option<u64> },
+}
 
 #[tokio::main]
-async fn main() {
-    let Some(path) = args().nth(1) else {
-        println!("usage: hath <config>");
-        return
+async fn main() -> ExitCode {
+    let args: Vec<String> = args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let Some((path, mode)) = parse_args(&args) else {
+        println!("{}", USAGE);
+        return ExitCode::FAILURE;
     };
 
-    let config = match hath::Config::from_file(&path) {
+    let config = match hath::Config::from_file(path) {
         Ok(config) => config,
         Err(e) => {
             println!("failed to load config: {}", e);
-            return
+            return ExitCode::FAILURE;
         }
     };
 
+    match mode {
+        Mode::Run => {}
+        Mode::Check { repair } => {
+            return match hath::check(config, repair).await {
+                Ok(true) => ExitCode::SUCCESS,
+                Ok(false) => ExitCode::FAILURE,
+                Err(e) => {
+                    println!("check failed: {:?}", e);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Mode::Warmup { list, concurrency, budget } => {
+            return match hath::warm_up(config, Path::new(list), concurrency, budget).await {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    println!("warm-up failed: {:?}", e);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+    }
+
     if let Err(e) = hath::main(config).await {
         println!("stopped unexpectedly: {:?}", e);
+        return ExitCode::FAILURE;
     }
+    ExitCode::SUCCESS
+}
+
+fn parse_args<'a>(args: &[&'a str]) -> Option<(&'a str, Mode<'a>)> {
+    let ret = match *args {
+        [path] if path != "check" && path != "warmup" => (path, Mode::Run),
+        ["check", path] => (path, Mode::Check { repair: false }),
+        ["check", path, "--repair"] => (path, Mode::Check { repair: true }),
+        ["warmup", path, list, ref options @ ..] => {
+            let (mut concurrency, mut budget) = (WARMUP_CONCURRENCY, None);
+            for option in options.chunks(2) {
+                match *option {
+                    ["--concurrency", n] => concurrency = n.parse().ok().filter(|n| *n > 0)?,
+                    ["--budget", n] => budget = Some(n.parse().ok()?),
+                    _ => return None,
+                }
+            }
+            (path, Mode::Warmup { list, concurrency, budget })
+        }
+        _ => return None,
+    };
+    Some(ret)
 }
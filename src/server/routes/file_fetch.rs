@@ -1,16 +1,27 @@
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use axum::body::Body;
 use axum::extract::{Path, State};
 use axum::response::IntoResponse;
-use http::header::CONTENT_TYPE;
+use http::header::{
+    ACCEPT_RANGES, CACHE_CONTROL, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE,
+    LAST_MODIFIED, RANGE,
+};
+use http::{HeaderMap, HeaderValue};
 use hyper::{Response, StatusCode};
 
 use crate::cache::{CacheFile, CacheStream};
 use crate::utils::sha1_digest;
-use crate::{Error, Result, ServerContext, unix_time};
+use crate::{Error, Result, ServerContext};
+
+/// Files never change once they are named by hash
+const CACHE_CONTROL_VALUE: HeaderValue = HeaderValue::from_static("public, max-age=31536000, immutable");
 
 pub(crate) async fn file_fetch(
     Path((file_id, extra)): Path<(String, String)>,
     State(ctx): State<ServerContext>,
+    headers: HeaderMap,
 ) -> Result<Response<Body>> {
     let extra = extra.split_once('/').map_or(extra.as_str(), |(x, _)| x);
 
@@ -25,18 +36,155 @@ pub(crate) async fn file_fetch(
     let time: u64 = time_str.parse()?;
 
     let hash = sha1_digest(&[time_str, file_id.as_str(), &ctx.key, "hotlinkthis"]);
-    if unix_time().abs_diff(time) > 900 || !hash[..10].eq(hash_part) {
+    if ctx.server_time().abs_diff(time) > 900 || !hash[..10].eq(hash_part) {
         return Err(Error::BadRequest);
     }
 
-    let stream = CacheStream::new(&ctx, &file, (data.fileindex, data.xres)).await?;
+    let etag = file.etag();
+    let if_range = headers.get(IF_RANGE);
+
+    // file is only stat when a date validator is given
+    let need_modified = match headers.get(IF_NONE_MATCH) {
+        Some(_) => false,
+        None => headers.contains_key(IF_MODIFIED_SINCE),
+    } || if_range.is_some_and(|v| !is_entity_tag(v));
+    let mut modified = None;
+    if need_modified {
+        let paths = ctx.cache_manager.lock().unwrap().locate(&file);
+        for (_, path) in paths {
+            if let Ok(metadata) = tokio::fs::metadata(path).await {
+                modified = metadata.modified().ok();
+                break;
+            }
+        }
+    }
+
+    let not_modified = match (headers.get(IF_NONE_MATCH), headers.get(IF_MODIFIED_SINCE)) {
+        (Some(value), _) => etag_matches(value, &etag),
+        (None, Some(value)) => match (parse_date(value), modified) {
+            (Some(since), Some(modified)) => truncate_secs(modified) <= since,
+            _ => false,
+        },
+        _ => false,
+    };
+    if not_modified {
+        let headers = [(ETAG, etag), (CACHE_CONTROL, CACHE_CONTROL_VALUE)];
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    let size = file.info.size;
+    let range = match headers.get(RANGE) {
+        Some(_) if if_range.is_some_and(|v| !if_range_matches(v, &etag, modified)) => ByteRange::Full,
+        Some(value) => ByteRange::parse(value, size),
+        None => ByteRange::Full,
+    };
+
+    let (status, range) = match range {
+        ByteRange::Full => (StatusCode::OK, 0..size),
+        ByteRange::Partial(range) => (StatusCode::PARTIAL_CONTENT, range),
+        ByteRange::Unsatisfiable => {
+            let content_range = HeaderValue::from_str(&format!("bytes */{}", size)).unwrap();
+            let res = (StatusCode::RANGE_NOT_SATISFIABLE, [(CONTENT_RANGE, content_range)]);
+            return Ok(res.into_response());
+        }
+    };
+
+    let stream = CacheStream::new(&ctx, &file, (data.fileindex, data.xres), range.clone()).await?;
     if let Some(s) = stream {
-        Ok(Response::builder().header(CONTENT_TYPE, file.info.typ.mine_type()).body(Body::new(s)).unwrap())
+        let mut builder = Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, file.info.typ.mine_type())
+            .header(ACCEPT_RANGES, HeaderValue::from_static("bytes"))
+            .header(ETAG, etag)
+            .header(CACHE_CONTROL, CACHE_CONTROL_VALUE);
+        if let Some(modified) = s.modified() {
+            builder = builder.header(LAST_MODIFIED, httpdate::fmt_http_date(modified));
+        }
+        if status == StatusCode::PARTIAL_CONTENT {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, size);
+            builder = builder.header(CONTENT_RANGE, content_range);
+        }
+        Ok(builder.body(Body::new(s)).unwrap())
     } else {
         Ok(StatusCode::NOT_FOUND.into_response())
     }
 }
 
+fn is_entity_tag(value: &HeaderValue) -> bool {
+    let bytes = value.as_bytes();
+    bytes.starts_with(b"\"") || bytes.starts_with(b"W/")
+}
+
+/// Weak comparison of `If-None-Match` against our strong entity tag.
+fn etag_matches(value: &HeaderValue, etag: &HeaderValue) -> bool {
+    let Ok(value) = value.to_str() else { return false };
+    value.trim() == "*" || value.split(',').map(|x| x.trim()).any(|x| x.trim_start_matches("W/") == etag)
+}
+
+/// `If-Range` only matches a strong validator.
+fn if_range_matches(value: &HeaderValue, etag: &HeaderValue, modified: Option<SystemTime>) -> bool {
+    if is_entity_tag(value) {
+        return value == etag;
+    }
+    match (parse_date(value), modified) {
+        (Some(date), Some(modified)) => truncate_secs(modified) == date,
+        _ => false,
+    }
+}
+
+fn parse_date(value: &HeaderValue) -> Option<SystemTime> {
+    value.to_str().ok().and_then(|s| httpdate::parse_http_date(s).ok())
+}
+
+/// HTTP date is in precision of seconds.
+fn truncate_secs(time: SystemTime) -> SystemTime {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parse `Range` header against a file of `size` bytes.
+    ///
+    /// Header in unknown format is ignored, while multiple ranges are rejected.
+    fn parse(value: &HeaderValue, size: u64) -> ByteRange {
+        let Some(spec) = value.to_str().ok().and_then(|s| s.trim().strip_prefix("bytes=")) else {
+            return ByteRange::Full;
+        };
+        if spec.contains(',') {
+            return ByteRange::Unsatisfiable;
+        }
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return ByteRange::Full;
+        };
+
+        let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+            // bytes=start-end
+            (Ok(start), Ok(end)) if start <= end => start..size.min(end.saturating_add(1)),
+            // bytes=start-
+            (Ok(start), Err(_)) if end.is_empty() => start..size,
+            // bytes=-suffix
+            (Err(_), Ok(suffix)) if start.is_empty() && suffix != 0 => size.saturating_sub(suffix)..size,
+            (Err(_), Ok(_)) if start.is_empty() => return ByteRange::Unsatisfiable,
+            _ => return ByteRange::Full,
+        };
+
+        if range.start >= size {
+            ByteRange::Unsatisfiable
+        } else if range == (0..size) {
+            ByteRange::Full
+        } else {
+            ByteRange::Partial(range)
+        }
+    }
+}
+
 struct FileFetchExtra<'a> {
     keystamp: &'a str,
     fileindex: &'a str,
@@ -44,7 +192,7 @@ struct FileFetchExtra<'a> {
 }
 
 impl FileFetchExtra<'_> {
-    fn from_path_parts(extra: &str) -> Option<FileFetchExtra> {
+    fn from_path_parts(extra: &str) -> Option<FileFetchExtra<'_>> {
         let mut keystamp = None;
         let mut fileindex = None;
         let mut xres = None;
@@ -70,7 +218,7 @@ impl FileFetchExtra<'_> {
 mod test {
     use std::io::Read;
 
-    //use super::*;
+    use super::*;
 
     #[tokio::test]
     async fn file_fetch() {
@@ -86,4 +234,41 @@ mod test {
         // request file
         // todo
     }
+
+    #[test]
+    fn byte_range() {
+        let parse = |s| ByteRange::parse(&HeaderValue::from_static(s), 1000);
+
+        assert_eq!(parse("bytes=0-499"), ByteRange::Partial(0..500));
+        assert_eq!(parse("bytes=500-"), ByteRange::Partial(500..1000));
+        assert_eq!(parse("bytes=-200"), ByteRange::Partial(800..1000));
+        assert_eq!(parse("bytes=900-2000"), ByteRange::Partial(900..1000));
+        assert_eq!(parse("bytes=0-"), ByteRange::Full);
+        assert_eq!(parse("bytes=-2000"), ByteRange::Full);
+
+        assert_eq!(parse("bytes=1000-"), ByteRange::Unsatisfiable);
+        assert_eq!(parse("bytes=-0"), ByteRange::Unsatisfiable);
+        assert_eq!(parse("bytes=0-1,5-9"), ByteRange::Unsatisfiable);
+
+        assert_eq!(parse("bytes=500-100"), ByteRange::Full);
+        assert_eq!(parse("bytes=abc"), ByteRange::Full);
+        assert_eq!(parse("items=0-1"), ByteRange::Full);
+    }
+
+    #[test]
+    fn validators() {
+        let etag = HeaderValue::from_static("\"5eb2e462781a2ba02cf435d6baa3573f4551c1a5\"");
+        let matches = |s| etag_matches(&HeaderValue::from_static(s), &etag);
+        assert!(matches("*"));
+        assert!(matches("\"5eb2e462781a2ba02cf435d6baa3573f4551c1a5\""));
+        assert!(matches("\"0000\", W/\"5eb2e462781a2ba02cf435d6baa3573f4551c1a5\""));
+        assert!(!matches("\"0000\""));
+
+        let modified = UNIX_EPOCH + Duration::from_secs(784111777) + Duration::from_millis(500);
+        let if_range = |s| if_range_matches(&HeaderValue::from_static(s), &etag, Some(modified));
+        assert!(if_range("\"5eb2e462781a2ba02cf435d6baa3573f4551c1a5\""));
+        assert!(!if_range("W/\"5eb2e462781a2ba02cf435d6baa3573f4551c1a5\""));
+        assert!(if_range("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!if_range("Sun, 06 Nov 1994 08:49:38 GMT"));
+    }
 }
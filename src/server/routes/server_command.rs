@@ -11,7 +11,7 @@ use hyper::{Response, Uri};
 
 use crate::client::downloader::download_gallery;
 use crate::utils::sha1_digest;
-use crate::{AppContext, Error, Result, ServerContext, unix_time};
+use crate::{AppContext, Error, Result, ServerContext};
 
 use super::SpeedTest;
 
@@ -31,7 +31,7 @@ pub(crate) async fn server_command(
         &ctx.key.to_string(),
     ]);
 
-    let sys_time = unix_time();
+    let sys_time = ctx.server_time();
     if !(time >= sys_time || sys_time - time <= 300) || key != digest {
         return Err(Error::BadRequest);
     }
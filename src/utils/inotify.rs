@@ -0,0 +1,106 @@
+use std::ffi::{CString, OsString};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+use tokio::io::unix::AsyncFd;
+
+/// Size of `inotify_event` without name
+const EVENT_SIZE: usize = size_of::<libc::inotify_event>();
+
+/// Watcher of directory changes, see inotify(7).
+pub struct Inotify {
+    fd: AsyncFd<OwnedFd>,
+}
+
+pub struct Event {
+    /// Watch descriptor returned by [`Inotify::add_watch`]
+    pub wd: i32,
+    pub mask: u32,
+    /// Name of file in the watched directory
+    pub name: Option<OsString>,
+}
+
+impl Inotify {
+    /// It must be called in tokio runtime.
+    pub fn new() -> io::Result<Inotify> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(Inotify { fd: AsyncFd::new(fd)? })
+    }
+
+    /// Watch a directory, return its watch descriptor.
+    pub fn add_watch(&self, path: &Path, mask: u32) -> io::Result<i32> {
+        let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| io::ErrorKind::InvalidInput)?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(wd)
+    }
+
+    /// Wait for events, `buf` should be large enough to hold a event with the longest name.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<Vec<Event>> {
+        let n = loop {
+            let mut guard = self.fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                if n < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(n as usize)
+            });
+            if let Ok(result) = result {
+                break result?;
+            }
+        };
+
+        let mut events = Vec::new();
+        let mut data = &buf[..n];
+        while data.len() >= EVENT_SIZE {
+            let event = unsafe { std::ptr::read_unaligned(data.as_ptr().cast::<libc::inotify_event>()) };
+            let end = (EVENT_SIZE + event.len as usize).min(data.len());
+            // name is padded with NUL
+            let name = data[EVENT_SIZE..end].split(|b| *b == 0).next().filter(|s| !s.is_empty());
+            events.push(Event {
+                wd: event.wd,
+                mask: event.mask,
+                name: name.map(|s| OsString::from_vec(s.to_vec())),
+            });
+            data = &data[end..];
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn events() {
+        let dir = std::env::temp_dir().join("hath-test-inotify");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let inotify = Inotify::new().unwrap();
+        let wd = inotify.add_watch(&dir, libc::IN_CLOSE_WRITE | libc::IN_DELETE).unwrap();
+        std::fs::write(dir.join("a"), b"hath").unwrap();
+        std::fs::remove_file(dir.join("a")).unwrap();
+
+        let mut buf = vec![0; 4096];
+        let mut events = Vec::new();
+        while events.len() < 2 {
+            events.extend(inotify.read(&mut buf).await.unwrap());
+        }
+        assert!(events.iter().all(|e| e.wd == wd && e.name.as_deref() == Some(OsStr::new("a"))));
+        assert_eq!(events[0].mask, libc::IN_CLOSE_WRITE);
+        assert_eq!(events[1].mask, libc::IN_DELETE);
+    }
+}
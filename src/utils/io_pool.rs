@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads dedicated to blocking file I/O.
+///
+/// Unlike the blocking pool of tokio, it never grows under load, and file reads don't
+/// queue behind other blocking tasks such as DNS resolving.
+#[derive(Clone)]
+pub struct IoPool {
+    sender: mpsc::Sender<Job>,
+}
+
+impl IoPool {
+    pub fn new(threads: usize) -> IoPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("hath-io-{}", i))
+                .spawn(move || {
+                    loop {
+                        // lock must be released before running the job
+                        let job = receiver.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            // every pool handle is dropped
+                            Err(_) => break,
+                        }
+                    }
+                })
+                .expect("failed to spawn I/O thread");
+        }
+        IoPool { sender }
+    }
+
+    /// Run `f` on the pool, its result is dropped if the returned future is gone.
+    pub fn run<F, T>(&self, f: F) -> Task<T>
+    where
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job = Box::new(move || {
+            let _ = tx.send(f());
+        });
+        let _ = self.sender.send(job);
+        Task(rx)
+    }
+}
+
+/// Result of a job running on [`IoPool`].
+pub struct Task<T>(oneshot::Receiver<io::Result<T>>);
+
+impl<T> Future for Task<T> {
+    type Output = io::Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = ready!(Pin::new(&mut self.0).poll(cx));
+        Poll::Ready(result.unwrap_or_else(|_| Err(io::Error::other("I/O thread is gone"))))
+    }
+}
+
+/// Read from `offset` of file without moving its cursor, so that a file could be shared.
+pub fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    #[cfg(unix)]
+    return std::os::unix::fs::FileExt::read_at(file, buf, offset);
+
+    #[cfg(windows)]
+    return std::os::windows::fs::FileExt::seek_read(file, buf, offset);
+}
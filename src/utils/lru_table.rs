@@ -1,35 +1,37 @@
-use std::collections::HashMap;
 use std::hash::Hash;
 use std::mem;
-use std::ptr::NonNull;
+
+use super::SlabMap;
 
 pub trait LruItem {
     type Key;
 
-    fn key(&self) -> Self::Key;
-
     fn key_ref(&self) -> &Self::Key;
 }
 
+/// Index of no node
+const NIL: u32 = u32::MAX;
+
+/// Items in order of recent use, nodes are linked by their indexes in [`SlabMap`].
+///
+/// Each item costs 8 bytes of links besides the cost of [`SlabMap`].
 pub struct LruTable<T: LruItem> {
-    table: HashMap<<T as LruItem>::Key, Box<Node<T>>>,
-    head: Option<NonNull<Node<T>>>,
-    tail: Option<NonNull<Node<T>>>,
+    slab: SlabMap<Node<T>>,
+    head: u32,
+    tail: u32,
 }
 
-pub struct Node<T> {
+struct Node<T> {
     value: T,
-    prev: Option<NonNull<Node<T>>>,
-    next: Option<NonNull<Node<T>>>,
+    prev: u32,
+    next: u32,
 }
 
-impl<T> Node<T> {
-    pub fn new(value: T) -> Node<T> {
-        Node {
-            value,
-            prev: None,
-            next: None,
-        }
+impl<T: LruItem> LruItem for Node<T> {
+    type Key = T::Key;
+
+    fn key_ref(&self) -> &Self::Key {
+        self.value.key_ref()
     }
 }
 
@@ -40,78 +42,151 @@ where
 {
     pub fn new() -> LruTable<T> {
         LruTable {
-            table: HashMap::new(),
-            head: None,
-            tail: None
+            slab: SlabMap::new(),
+            head: NIL,
+            tail: NIL,
         }
     }
 
     pub fn get(&mut self, key: &<T as LruItem>::Key) -> Option<&mut T> {
-        let mut node = self.table.get_mut(key);
-        if let Some(node) = &mut node {
-            LruTable::dettach(node.as_mut(), &mut self.head, &mut self.tail);
-            LruTable::attach_front(node.as_mut(), &mut self.head, &mut self.tail);
-        }
-        node.map(|n| &mut n.value)
+        let index = self.slab.find(key)?;
+        self.dettach(index);
+        self.attach_front(index);
+        Some(&mut self.slab.get_mut(index).value)
+    }
+
+    /// Get item without marking it as recently used.
+    pub fn peek(&self, key: &<T as LruItem>::Key) -> Option<&T> {
+        self.slab.find(key).map(|index| &self.slab.get(index).value)
+    }
+
+    pub fn contains(&self, key: &<T as LruItem>::Key) -> bool {
+        self.slab.find(key).is_some()
     }
 
     pub fn push_front(&mut self, value: T) -> Option<T> {
-        use std::collections::hash_map::Entry;
-
-        let mut previous = None;
-        let node = match self.table.entry(value.key()) {
-            Entry::Occupied(mut entry) => {
-                // replace existed value to avoid alloc
-                previous = Some(mem::replace(&mut entry.get_mut().value, value));
-                entry.into_mut()
-            }
-            Entry::Vacant(entry) => entry.insert(Box::new(Node::new(value))),
+        let Some(index) = self.slab.find(value.key_ref()) else {
+            let index = self.slab.insert(Node { value, prev: NIL, next: NIL });
+            self.attach_front(index);
+            return None;
         };
 
-        LruTable::attach_front(node.as_mut(), &mut self.head, &mut self.tail);
-        previous
+        let previous = mem::replace(&mut self.slab.get_mut(index).value, value);
+        self.dettach(index);
+        self.attach_front(index);
+        Some(previous)
+    }
+
+    pub fn remove(&mut self, key: &<T as LruItem>::Key) -> Option<T> {
+        let index = self.slab.find(key)?;
+        Some(self.remove_at(index))
     }
 
     pub fn pop_back(&mut self) -> Option<T> {
-        self.tail.map(|node| unsafe {
-            let key = node.as_ref().value.key_ref();
-            let mut node = self.table.remove(key).unwrap_unchecked();
-            LruTable::dettach(&mut node, &mut self.head, &mut self.tail);
-            node.value
-        })
+        (self.tail != NIL).then(|| self.remove_at(self.tail))
     }
 
-    fn attach_front(node: &mut Node<T>, head: &mut Option<NonNull<Node<T>>>, tail: &mut Option<NonNull<Node<T>>>) {
-        node.prev = None;
-        node.next = *head;
+    /// Iterate from the most recently used one.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            table: self,
+            head: self.head,
+            tail: self.tail,
+            len: self.slab.len(),
+        }
+    }
+
+    fn remove_at(&mut self, index: u32) -> T {
+        self.dettach(index);
+        let node = self.slab.remove_at(index);
 
-        let node_ptr = NonNull::new(node).unwrap();
-        if let Some(head) = head {
-            unsafe { head.as_mut().prev = Some(node_ptr) };
+        // the last node is moved into the removed one, link it again
+        if (index as usize) < self.slab.len() {
+            let Node { prev, next, .. } = *self.slab.get(index);
+            match prev {
+                NIL => self.head = index,
+                prev => self.slab.get_mut(prev).next = index,
+            }
+            match next {
+                NIL => self.tail = index,
+                next => self.slab.get_mut(next).prev = index,
+            }
         }
-        *head = Some(node_ptr);
+        node.value
+    }
+
+    fn attach_front(&mut self, index: u32) {
+        let head = self.head;
+        let node = self.slab.get_mut(index);
+        node.prev = NIL;
+        node.next = head;
 
-        if tail.is_none() {
-            *tail = Some(node_ptr);
+        match head {
+            NIL => self.tail = index,
+            head => self.slab.get_mut(head).prev = index,
         }
+        self.head = index;
     }
 
-    fn dettach(node: &mut Node<T>, head: &mut Option<NonNull<Node<T>>>, tail: &mut Option<NonNull<Node<T>>>) {
-        if let Some(mut prev) = node.prev {
-            unsafe { prev.as_mut().next = node.next }
-        } else {
+    fn dettach(&mut self, index: u32) {
+        let Node { prev, next, .. } = *self.slab.get(index);
+        match prev {
             // is in head
-            *head = node.next
+            NIL => self.head = next,
+            prev => self.slab.get_mut(prev).next = next,
         }
-        if let Some(mut next) = node.next {
-            unsafe { next.as_mut().prev = node.prev }
-        } else {
+        match next {
             // is in tail
-            *tail = node.prev
+            NIL => self.tail = prev,
+            next => self.slab.get_mut(next).prev = prev,
         }
     }
 }
 
+pub struct Iter<'a, T: LruItem> {
+    table: &'a LruTable<T>,
+    head: u32,
+    tail: u32,
+    len: usize,
+}
+
+impl<'a, T> Iterator for Iter<'a, T>
+where
+    T: LruItem,
+    <T as LruItem>::Key: Eq + Hash,
+{
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.len == 0 {
+            return None;
+        }
+        let node = self.table.slab.get(self.head);
+        self.len -= 1;
+        self.head = node.next;
+        Some(&node.value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<T> DoubleEndedIterator for Iter<'_, T>
+where
+    T: LruItem,
+    <T as LruItem>::Key: Eq + Hash,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.len == 0 {
+            return None;
+        }
+        let node = self.table.slab.get(self.tail);
+        self.len -= 1;
+        self.tail = node.prev;
+        Some(&node.value)
+    }
+}
 
 #[cfg(test)]
 mod test {
@@ -119,7 +194,6 @@ mod test {
 
     impl LruItem for i32 {
         type Key = i32;
-        fn key(&self) -> Self::Key { *self }
         fn key_ref(&self) -> &Self::Key { self }
     }
 
@@ -137,6 +211,9 @@ mod test {
         table.get(&2); // 2, 5, 6, 4, 3, 1
         table.get(&1); // 1, 2, 5, 6, 4, 3
 
+        assert_eq!(table.iter().copied().collect::<Vec<_>>(), [1, 2, 5, 6, 4, 3]);
+        assert_eq!(table.iter().rev().copied().collect::<Vec<_>>(), [3, 4, 6, 5, 2, 1]);
+
         assert_eq!(table.pop_back(), Some(3));
         assert_eq!(table.pop_back(), Some(4));
         assert_eq!(table.pop_back(), Some(6));
@@ -145,4 +222,21 @@ mod test {
         assert_eq!(table.pop_back(), Some(1));
         assert_eq!(table.pop_back(), None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn remove() {
+        let mut table = LruTable::new();
+        table.push_front(1);
+        table.push_front(2);
+        table.push_front(3); // 3, 2, 1
+
+        assert_eq!(table.remove(&2), Some(2)); // 3, 1
+        assert_eq!(table.remove(&2), None);
+        assert_eq!(table.remove(&1), Some(1)); // 3
+
+        table.push_front(4); // 4, 3
+        assert_eq!(table.pop_back(), Some(3));
+        assert_eq!(table.pop_back(), Some(4));
+        assert_eq!(table.pop_back(), None);
+    }
+}
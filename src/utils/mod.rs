@@ -2,17 +2,26 @@ use std::io;
 use std::time::Duration;
 
 use openssl::sha::Sha1;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
 pub mod body;
 pub use self::body::BoxBody;
 
+#[cfg(target_os = "linux")]
+pub mod inotify;
+
+pub mod io_pool;
+pub use self::io_pool::{IoPool, Task, read_at};
+
 pub mod limiter;
 pub use self::limiter::{LimitedStream, Limiter};
 
 mod lru_table;
 pub use lru_table::*;
 
+mod slab_map;
+pub use slab_map::SlabMap;
+
 pub fn hex_to_u8(h0: u8, h1: u8) -> Option<u8> {
     let n0 = match h0 {
         b'0'..=b'9' => h0 - b'0',
@@ -82,11 +91,12 @@ pub fn sha1_digest(data: &[&str]) -> String {
     slice_to_hex(&digest)
 }
 
-pub async fn file_sha1(file: &mut tokio::fs::File) -> io::Result<String> {
+/// Return a hexadecimal sha1 digest of all bytes read from `reader`.
+pub async fn file_sha1<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<String> {
     let mut buf = vec![0; 4096];
     let mut hasher = Sha1::new();
     loop {
-        let n = file.read(&mut buf).await?;
+        let n = reader.read(&mut buf).await?;
         if n == 0 {
             break;
         }
@@ -97,6 +107,43 @@ pub async fn file_sha1(file: &mut tokio::fs::File) -> io::Result<String> {
     Ok(slice_to_hex(&digest))
 }
 
+/// Replace file with `data` through a temporary file, so that it's either the old or the new one after a crash.
+pub async fn write_atomic(path: &std::path::Path, data: &[u8]) -> io::Result<()> {
+    let mut temp = path.to_path_buf();
+    temp.as_mut_os_string().push(".tmp");
+    let mut file = tokio::fs::File::create(&temp).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&temp, path).await?;
+
+    // persist the rename as well
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { std::path::Path::new(".") } else { dir };
+        tokio::fs::File::open(dir).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Space available to unprivileged users on the filesystem of `path`.
+#[cfg(unix)]
+pub fn disk_free(path: &std::path::Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| io::ErrorKind::InvalidInput)?;
+    let mut stat = unsafe { std::mem::zeroed::<libc::statvfs>() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn disk_free(_path: &std::path::Path) -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
 
 // todo: u64 or string ?
 pub fn unix_time() -> u64 {
@@ -0,0 +1,74 @@
+use std::hash::{BuildHasher, Hash, RandomState};
+
+use hashbrown::HashTable;
+
+use super::LruItem;
+
+/// Items kept densely in a `Vec`, and looked up by their keys through a table of indexes.
+///
+/// Keys are not stored twice, so each item costs its own size plus about 6 bytes of the table.
+/// Removing an item moves the last one into its place.
+pub struct SlabMap<T> {
+    items: Vec<T>,
+    table: HashTable<u32>,
+    state: RandomState,
+}
+
+impl<T> SlabMap<T>
+where
+    T: LruItem,
+    <T as LruItem>::Key: Eq + Hash,
+{
+    pub fn new() -> SlabMap<T> {
+        SlabMap {
+            items: Vec::new(),
+            table: HashTable::new(),
+            state: RandomState::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn find(&self, key: &<T as LruItem>::Key) -> Option<u32> {
+        let hash = self.state.hash_one(key);
+        self.table.find(hash, |i| self.items[*i as usize].key_ref() == key).copied()
+    }
+
+    pub fn get(&self, index: u32) -> &T {
+        &self.items[index as usize]
+    }
+
+    pub fn get_mut(&mut self, index: u32) -> &mut T {
+        &mut self.items[index as usize]
+    }
+
+    /// Insert an item whose key is not present yet, return its index.
+    pub fn insert(&mut self, value: T) -> u32 {
+        let index = u32::try_from(self.items.len()).expect("too many items");
+        let hash = self.state.hash_one(value.key_ref());
+        self.items.push(value);
+
+        let (items, state) = (&self.items, &self.state);
+        self.table.insert_unique(hash, index, |i| state.hash_one(items[*i as usize].key_ref()));
+        index
+    }
+
+    /// Remove the item at `index`, the last item is moved into `index` if it's not the last one.
+    pub fn remove_at(&mut self, index: u32) -> T {
+        let hash = self.state.hash_one(self.items[index as usize].key_ref());
+        if let Ok(entry) = self.table.find_entry(hash, |i| *i == index) {
+            entry.remove();
+        }
+
+        let last = self.items.len() as u32 - 1;
+        if index != last {
+            let hash = self.state.hash_one(self.items[last as usize].key_ref());
+            if let Some(i) = self.table.find_mut(hash, |i| *i == last) {
+                *i = index;
+            }
+        }
+        self.items.swap_remove(index as usize)
+    }
+}